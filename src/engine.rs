@@ -0,0 +1,278 @@
+// Thin wrapper around the regex engines used by the atoms, which match bytes
+// rather than text so that inputs need not be valid UTF-8. By default this is
+// the `regex` crate; with the `fancy-regex` feature enabled, the backtracking
+// engine from `fancy-regex` is available as well, which adds support for
+// lookaround and backreferences. The latter is only used for patterns that
+// need any of its additional features, and can't match invalid UTF-8.
+use crate::replacement::Replacement;
+use anyhow::Result;
+use std::ops::Range;
+
+// Engine used for a regex unless its pattern starts with `(*REGEX)` or
+// `(*FANCY)` to choose one of its own
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Engine {
+    Regex,
+    Fancy,
+}
+
+impl Default for Engine {
+    // the engine the crate was built for
+    fn default() -> Engine {
+        if cfg!(feature = "fancy-regex") {
+            Engine::Fancy
+        } else {
+            Engine::Regex
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Inner {
+    Regex(regex::bytes::Regex),
+    #[cfg(feature = "fancy-regex")]
+    Fancy(fancy_regex::Regex),
+}
+
+#[derive(Debug, Clone)]
+pub struct Regex(Inner);
+
+// Groups of a single match of either engine
+pub enum Captures<'t> {
+    Regex(regex::bytes::Captures<'t>),
+    #[cfg(feature = "fancy-regex")]
+    Fancy(fancy_regex::Captures<'t>),
+}
+
+// Group of a match, as found by either engine
+#[derive(Debug, PartialEq, Clone)]
+pub struct Match<'t> {
+    value: &'t [u8],
+    start: usize,
+}
+
+impl<'t> Match<'t> {
+    pub fn as_bytes(&self) -> &'t [u8] {
+        self.value
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.start + self.value.len()
+    }
+}
+
+impl<'t> From<regex::bytes::Match<'t>> for Match<'t> {
+    fn from(m: regex::bytes::Match<'t>) -> Self {
+        Match {
+            value: m.as_bytes(),
+            start: m.start(),
+        }
+    }
+}
+
+#[cfg(feature = "fancy-regex")]
+impl<'t> From<fancy_regex::Match<'t>> for Match<'t> {
+    fn from(m: fancy_regex::Match<'t>) -> Self {
+        Match {
+            value: m.as_str().as_bytes(),
+            start: m.start(),
+        }
+    }
+}
+
+impl<'t> Captures<'t> {
+    pub fn get(&self, i: usize) -> Option<Match<'t>> {
+        match self {
+            Captures::Regex(caps) => caps.get(i).map(From::from),
+            #[cfg(feature = "fancy-regex")]
+            Captures::Fancy(caps) => caps.get(i).map(From::from),
+        }
+    }
+
+    pub fn name(&self, name: &str) -> Option<Match<'t>> {
+        match self {
+            Captures::Regex(caps) => caps.name(name).map(From::from),
+            #[cfg(feature = "fancy-regex")]
+            Captures::Fancy(caps) => caps.name(name).map(From::from),
+        }
+    }
+}
+
+// Named group of a match
+#[derive(Debug, PartialEq, Clone)]
+pub struct Capture {
+    pub name: String,
+    pub value: Vec<u8>,
+    pub range: Option<Range<usize>>, // in the matched text, as long as it is known
+}
+
+impl Regex {
+    // Uses the default engine unless the pattern chooses one
+    #[cfg(test)]
+    pub fn new(re: &str) -> Result<Regex> {
+        Regex::with_engine(re, Engine::default())
+    }
+
+    pub fn with_engine(re: &str, engine: Engine) -> Result<Regex> {
+        let (engine, re) = if let Some(re) = re.strip_prefix("(*REGEX)") {
+            (Engine::Regex, re)
+        } else if let Some(re) = re.strip_prefix("(*FANCY)") {
+            (Engine::Fancy, re)
+        } else {
+            (engine, re)
+        };
+        let regex = || {
+            regex::bytes::Regex::new(re)
+                .map(|re| Regex(Inner::Regex(re)))
+                .map_err(|err| match err {
+                    regex::Error::Syntax(m) => anyhow::Error::msg(m),
+                    _ => anyhow::Error::msg(format!("Invalid regular expression: {}", re)),
+                })
+        };
+        match engine {
+            Engine::Regex => regex(),
+            // the `regex` crate takes any pattern it supports, like
+            // `fancy-regex` would do on its own
+            #[cfg(feature = "fancy-regex")]
+            Engine::Fancy => regex().or_else(|_| {
+                fancy_regex::Regex::new(re)
+                    .map(|re| Regex(Inner::Fancy(re)))
+                    .map_err(|err| anyhow::Error::msg(format!("{}: {}", err, re)))
+            }),
+            #[cfg(not(feature = "fancy-regex"))]
+            Engine::Fancy => Err(anyhow::Error::msg(
+                "The fancy engine is not available, saw was built without the fancy-regex feature",
+            )),
+        }
+    }
+
+    pub fn captures_iter<'r, 't>(
+        &'r self,
+        text: &'t [u8],
+    ) -> Box<dyn Iterator<Item = Result<Captures<'t>>> + 'r>
+    where
+        't: 'r,
+    {
+        match &self.0 {
+            Inner::Regex(re) => {
+                Box::new(re.captures_iter(text).map(|caps| Ok(Captures::Regex(caps))))
+            }
+            #[cfg(feature = "fancy-regex")]
+            Inner::Fancy(re) => match self.text(text) {
+                Ok(text) => Box::new(re.captures_iter(text).map(|caps| match caps {
+                    Ok(caps) => Ok(Captures::Fancy(caps)),
+                    Err(err) => Err(err.into()),
+                })),
+                Err(err) => Box::new(std::iter::once(Err(err))),
+            },
+        }
+    }
+
+    // The text the fancy engine needs to match
+    #[cfg(feature = "fancy-regex")]
+    fn text<'t>(&self, bytes: &'t [u8]) -> Result<&'t str> {
+        std::str::from_utf8(bytes).map_err(|_| {
+            anyhow::anyhow!(
+                "Invalid UTF-8 can't be matched by '{}', which needs the fancy engine",
+                self.as_str()
+            )
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        match &self.0 {
+            Inner::Regex(re) => re.as_str(),
+            #[cfg(feature = "fancy-regex")]
+            Inner::Fancy(re) => re.as_str(),
+        }
+    }
+
+    pub fn is_match(&self, text: &[u8]) -> Result<bool> {
+        match &self.0 {
+            Inner::Regex(re) => Ok(re.is_match(text)),
+            #[cfg(feature = "fancy-regex")]
+            Inner::Fancy(re) => Ok(re.is_match(self.text(text)?)?),
+        }
+    }
+
+    // Range of the first match, which is cheaper to find than its groups
+    pub fn find(&self, text: &[u8]) -> Result<Option<Range<usize>>> {
+        match &self.0 {
+            Inner::Regex(re) => Ok(re.find(text).map(|m| m.range())),
+            #[cfg(feature = "fancy-regex")]
+            Inner::Fancy(re) => Ok(re.find(self.text(text)?)?.map(|m| m.range())),
+        }
+    }
+
+    pub fn captures<'t>(&self, text: &'t [u8]) -> Result<Option<Captures<'t>>> {
+        match &self.0 {
+            Inner::Regex(re) => Ok(re.captures(text).map(Captures::Regex)),
+            #[cfg(feature = "fancy-regex")]
+            Inner::Fancy(re) => Ok(re.captures(self.text(text)?)?.map(Captures::Fancy)),
+        }
+    }
+
+    fn capture_names(&self) -> Box<dyn Iterator<Item = Option<&str>> + '_> {
+        match &self.0 {
+            Inner::Regex(re) => Box::new(re.capture_names()),
+            #[cfg(feature = "fancy-regex")]
+            Inner::Fancy(re) => Box::new(re.capture_names()),
+        }
+    }
+
+    pub fn has_names(&self) -> bool {
+        self.capture_names().any(|name| name.is_some())
+    }
+
+    // The named groups that participated in the match
+    pub fn named_captures(&self, caps: &Captures) -> Vec<Capture> {
+        self.capture_names()
+            .enumerate()
+            .filter_map(|(i, name)| {
+                let m = caps.get(i)?;
+                Some(Capture {
+                    name: name?.to_owned(),
+                    value: m.as_bytes().to_vec(),
+                    range: Some(m.range()),
+                })
+            })
+            .collect()
+    }
+
+    // Replaces the matches whose (1-based) position is accepted by `select`,
+    // passing each replaced match and the range of its replacement in the
+    // result to `replaced`; returns `None` if no match was replaced. Matches
+    // after position `until`, if given, are not searched for.
+    pub fn replace_selected(
+        &self,
+        text: &[u8],
+        replacement: &Replacement,
+        mut select: impl FnMut(usize) -> bool,
+        until: Option<usize>,
+        mut replaced: impl FnMut(&Captures, Range<usize>),
+    ) -> Result<Option<Vec<u8>>> {
+        let mut res = Vec::with_capacity(text.len());
+        let mut last = 0;
+        let mut any = false;
+        let matches = self.captures_iter(text).take(until.unwrap_or(usize::MAX));
+        for (n, caps) in matches.enumerate() {
+            let caps = caps?;
+            if !select(n + 1) {
+                continue;
+            }
+            // the whole match is always present
+            let m = caps.get(0).unwrap().range();
+            res.extend_from_slice(&text[last..m.start]);
+            let start = res.len();
+            replacement.expand(&caps, &mut res);
+            replaced(&caps, start..res.len());
+            last = m.end;
+            any = true;
+        }
+        if !any {
+            return Ok(None);
+        }
+        res.extend_from_slice(&text[last..]);
+        Ok(Some(res))
+    }
+}
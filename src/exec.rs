@@ -0,0 +1,114 @@
+// Running of shell commands for the `exec` atoms
+use crate::Result;
+use anyhow::{bail, Context};
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use wait_timeout::ChildExt;
+
+// Quotes a value to be used as a single word by the shell
+pub fn quote(value: &[u8]) -> Vec<u8> {
+    let mut res = vec![b'\''];
+    for &b in value {
+        match b {
+            b'\'' => res.extend_from_slice(br"'\''"),
+            b => res.push(b),
+        }
+    }
+    res.push(b'\'');
+    res
+}
+
+// Arguments and file names may be any bytes on Unix
+#[cfg(unix)]
+pub fn os_string(bytes: Vec<u8>) -> OsString {
+    std::os::unix::ffi::OsStringExt::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+pub fn os_string(bytes: Vec<u8>) -> OsString {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+// Runs `command` with `sh -c`, feeding it `input`, and returns its output
+// without the final newline. Fails if the command does not succeed or, with a
+// `timeout`, takes longer, in which case all processes it started are killed.
+pub fn run(command: &[u8], input: Vec<u8>, timeout: Option<Duration>) -> Result<Vec<u8>> {
+    let mut sh = Command::new("sh");
+    sh.arg("-c")
+        .arg(os_string(command.to_vec()))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // the shell leads a process group of its own, which takes along the
+    // commands it starts when killed
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut sh, 0);
+    let command = String::from_utf8_lossy(command);
+    let mut child = sh
+        .spawn()
+        .with_context(|| format!("Could not run '{}'", command))?;
+
+    // the pipes are served by threads so that neither side blocks on a full
+    // pipe; the pipes are always set up above, so safe to unwrap
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let stdout = read_to_end(child.stdout.take().unwrap());
+    let stderr = read_to_end(child.stderr.take().unwrap());
+
+    let status = match timeout {
+        Some(timeout) => match child.wait_timeout(timeout)? {
+            Some(status) => Some(status),
+            None => {
+                kill(&mut child)?;
+                child.wait()?;
+                None
+            }
+        },
+        None => Some(child.wait()?),
+    };
+    // commands may exit without reading all of their input
+    let _ = writer.join();
+    let stdout = stdout.join().unwrap()?;
+    let stderr = stderr.join().unwrap()?;
+    let status = match status {
+        Some(status) => status,
+        None => bail!("'{}' timed out after {:?}", command, timeout.unwrap()),
+    };
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        match stderr.trim_end() {
+            "" => bail!("'{}' failed with {}", command, status),
+            stderr => bail!("'{}' failed with {}: {}", command, status, stderr),
+        }
+    }
+
+    let mut output = stdout;
+    if output.ends_with(b"\n") {
+        output.pop();
+    }
+    Ok(output)
+}
+
+#[cfg(unix)]
+fn kill(child: &mut Child) -> Result<()> {
+    // the group is led by the child, so its ID is the one of the child
+    if unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) -> Result<()> {
+    Ok(child.kill()?)
+}
+
+fn read_to_end(mut pipe: impl Read + Send + 'static) -> JoinHandle<std::io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        pipe.read_to_end(&mut buf).map(|_| buf)
+    })
+}
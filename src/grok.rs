@@ -0,0 +1,228 @@
+// Named sub-patterns in the style of logstash's grok that can be used in
+// regexes as `%{NAME}` or `%{NAME:capture}`. The built-in definitions are
+// adapted from logstash's patterns to the syntax of the regex crate.
+use crate::Result;
+use anyhow::{bail, Context};
+use std::collections::HashMap;
+use std::fs;
+
+static BUILTIN: &[(&str, &str)] = &[
+    ("USERNAME", r"[a-zA-Z0-9._-]+"),
+    ("USER", r"%{USERNAME}"),
+    (
+        "EMAILLOCALPART",
+        r"[a-zA-Z0-9!#$%&'*+\-/=?^_`|~]+(?:\.[a-zA-Z0-9!#$%&'*+\-/=?^_`|~]+)*",
+    ),
+    ("EMAILADDRESS", r"%{EMAILLOCALPART}@%{HOSTNAME}"),
+    ("INT", r"(?:[+-]?(?:[0-9]+))"),
+    ("BASE10NUM", r"(?:[+-]?(?:[0-9]+(?:\.[0-9]+)?|\.[0-9]+))"),
+    ("NUMBER", r"(?:%{BASE10NUM})"),
+    ("BASE16NUM", r"(?:0[xX])?[0-9A-Fa-f]+"),
+    ("POSINT", r"\b(?:[1-9][0-9]*)\b"),
+    ("NONNEGINT", r"\b(?:[0-9]+)\b"),
+    ("WORD", r"\b\w+\b"),
+    ("NOTSPACE", r"\S+"),
+    ("SPACE", r"\s*"),
+    ("DATA", r".*?"),
+    ("GREEDYDATA", r".*"),
+    ("QUOTEDSTRING", r#"(?:"(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)*')"#),
+    ("QS", r"%{QUOTEDSTRING}"),
+    (
+        "UUID",
+        r"[A-Fa-f0-9]{8}-(?:[A-Fa-f0-9]{4}-){3}[A-Fa-f0-9]{12}",
+    ),
+    (
+        "MAC",
+        r"(?:(?:[A-Fa-f0-9]{2}[:-]){5}[A-Fa-f0-9]{2}|(?:[A-Fa-f0-9]{4}\.){2}[A-Fa-f0-9]{4})",
+    ),
+    (
+        "IPV4",
+        r"\b(?:(?:25[0-5]|2[0-4][0-9]|[01]?[0-9]{1,2})\.){3}(?:25[0-5]|2[0-4][0-9]|[01]?[0-9]{1,2})\b",
+    ),
+    (
+        "IPV6",
+        r"(?:(?:[0-9A-Fa-f]{1,4}:){7}[0-9A-Fa-f]{1,4}|(?:[0-9A-Fa-f]{1,4}:){1,6}:[0-9A-Fa-f]{1,4}|(?:[0-9A-Fa-f]{1,4}:){1,5}(?::[0-9A-Fa-f]{1,4}){1,2}|(?:[0-9A-Fa-f]{1,4}:){1,4}(?::[0-9A-Fa-f]{1,4}){1,3}|(?:[0-9A-Fa-f]{1,4}:){1,3}(?::[0-9A-Fa-f]{1,4}){1,4}|(?:[0-9A-Fa-f]{1,4}:){1,2}(?::[0-9A-Fa-f]{1,4}){1,5}|[0-9A-Fa-f]{1,4}:(?::[0-9A-Fa-f]{1,4}){1,6}|(?:[0-9A-Fa-f]{1,4}:){1,7}:|:(?:(?::[0-9A-Fa-f]{1,4}){1,7}|:))",
+    ),
+    ("IP", r"(?:%{IPV6}|%{IPV4})"),
+    (
+        "HOSTNAME",
+        r"\b(?:[0-9A-Za-z][0-9A-Za-z-]{0,62})(?:\.(?:[0-9A-Za-z][0-9A-Za-z-]{0,62}))*\b",
+    ),
+    ("IPORHOST", r"(?:%{IP}|%{HOSTNAME})"),
+    ("HOSTPORT", r"%{IPORHOST}:%{POSINT}"),
+    ("UNIXPATH", r"(?:/[\w%!$@:.,+~-]*)+"),
+    ("PATH", r"%{UNIXPATH}"),
+    ("URIPROTO", r"[A-Za-z][A-Za-z0-9+\-.]*"),
+    ("URIHOST", r"%{IPORHOST}(?::%{POSINT})?"),
+    ("URIPATH", r"(?:/[A-Za-z0-9$.+!*'(){},~:;=@#%&_\-]*)+"),
+    ("URIPARAM", r"\?[A-Za-z0-9$.+!*'|(){},~@#%&/=:;_?\-\[\]<>]*"),
+    ("URIPATHPARAM", r"%{URIPATH}(?:%{URIPARAM})?"),
+    (
+        "URI",
+        r"%{URIPROTO}://(?:%{USER}(?::[^@]*)?@)?(?:%{URIHOST})?(?:%{URIPATHPARAM})?",
+    ),
+    (
+        "MONTH",
+        r"\b(?:[Jj]an(?:uary)?|[Ff]eb(?:ruary)?|[Mm]ar(?:ch)?|[Aa]pr(?:il)?|[Mm]ay|[Jj]un(?:e)?|[Jj]ul(?:y)?|[Aa]ug(?:ust)?|[Ss]ep(?:tember)?|[Oo]ct(?:ober)?|[Nn]ov(?:ember)?|[Dd]ec(?:ember)?)\b",
+    ),
+    ("MONTHNUM", r"(?:0?[1-9]|1[0-2])"),
+    ("MONTHDAY", r"(?:(?:0[1-9])|(?:[12][0-9])|(?:3[01])|[1-9])"),
+    (
+        "DAY",
+        r"(?:Mon(?:day)?|Tue(?:sday)?|Wed(?:nesday)?|Thu(?:rsday)?|Fri(?:day)?|Sat(?:urday)?|Sun(?:day)?)",
+    ),
+    ("YEAR", r"(?:\d\d){1,2}"),
+    ("HOUR", r"(?:2[0123]|[01]?[0-9])"),
+    ("MINUTE", r"(?:[0-5][0-9])"),
+    ("SECOND", r"(?:(?:[0-5]?[0-9]|60)(?:[:.,][0-9]+)?)"),
+    ("TIME", r"%{HOUR}:%{MINUTE}(?::%{SECOND})?"),
+    ("DATE_US", r"%{MONTHNUM}[/-]%{MONTHDAY}[/-]%{YEAR}"),
+    ("DATE_EU", r"%{MONTHDAY}[./-]%{MONTHNUM}[./-]%{YEAR}"),
+    ("DATE", r"(?:%{DATE_US}|%{DATE_EU})"),
+    ("DATESTAMP", r"%{DATE}[- ]%{TIME}"),
+    ("ISO8601_TIMEZONE", r"(?:Z|[+-]%{HOUR}(?::?%{MINUTE}))"),
+    (
+        "TIMESTAMP_ISO8601",
+        r"%{YEAR}-%{MONTHNUM}-%{MONTHDAY}[T ]%{HOUR}:?%{MINUTE}(?::?%{SECOND})?%{ISO8601_TIMEZONE}?",
+    ),
+    ("HTTPDATE", r"%{MONTHDAY}/%{MONTH}/%{YEAR}:%{TIME} %{INT}"),
+    ("SYSLOGTIMESTAMP", r"%{MONTH} +%{MONTHDAY} %{TIME}"),
+    ("PROG", r"[\x21-\x5a\x5c\x5e-\x7e]+"),
+    ("SYSLOGPROG", r"%{PROG:program}(?:\[%{POSINT:pid}\])?"),
+    ("SYSLOGHOST", r"%{IPORHOST}"),
+    (
+        "SYSLOGBASE",
+        r"%{SYSLOGTIMESTAMP:timestamp} (?:%{SYSLOGHOST:logsource} )?%{SYSLOGPROG}:",
+    ),
+    (
+        "LOGLEVEL",
+        r"(?:[Aa]lert|ALERT|[Tt]race|TRACE|[Dd]ebug|DEBUG|[Nn]otice|NOTICE|[Ii]nfo?(?:rmation)?|INFO?(?:RMATION)?|[Ww]arn?(?:ing)?|WARN?(?:ING)?|[Ee]rr?(?:or)?|ERR?(?:OR)?|[Cc]rit?(?:ical)?|CRIT?(?:ICAL)?|[Ff]atal|FATAL|[Ss]evere|SEVERE|EMERG(?:ENCY)?|[Ee]merg(?:ency)?)",
+    ),
+    (
+        "COMMONAPACHELOG",
+        r#"%{IPORHOST:clientip} %{USER:ident} %{USER:auth} \[%{HTTPDATE:timestamp}\] "(?:%{WORD:verb} %{NOTSPACE:request}(?: HTTP/%{NUMBER:httpversion})?|%{DATA:rawrequest})" %{NUMBER:response} (?:%{NUMBER:bytes}|-)"#,
+    ),
+    (
+        "COMBINEDAPACHELOG",
+        r"%{COMMONAPACHELOG} %{QS:referrer} %{QS:agent}",
+    ),
+    ("NGINXACCESS", r"%{COMBINEDAPACHELOG}"),
+];
+
+#[derive(Debug)]
+pub struct Patterns {
+    definitions: HashMap<String, String>,
+    reference: regex::Regex,
+}
+
+impl Patterns {
+    // Contains the built-in definitions only
+    pub fn new() -> Patterns {
+        Patterns {
+            definitions: BUILTIN
+                .iter()
+                .map(|(name, def)| (name.to_string(), def.to_string()))
+                .collect(),
+            // `%{NAME}`, `%{NAME:capture}` or `%{NAME:capture:type}`; the type is ignored
+            reference: regex::Regex::new(r"%\{([A-Za-z_]\w*)(?::(\w+))?(?::\w+)?\}").unwrap(),
+        }
+    }
+
+    // Adds the definitions from a file containing lines of the form
+    // `NAME pattern`; empty lines and lines starting with `#` are skipped.
+    pub fn load(&mut self, path: &str) -> Result<()> {
+        let content =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(char::is_whitespace) {
+                Some((name, def)) => {
+                    self.definitions
+                        .insert(name.to_owned(), def.trim_start().to_owned());
+                }
+                None => bail!("{}:{}: Missing definition of '{}'", path, n + 1, line),
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces all pattern references in `re`, recursively; references to
+    // unknown patterns match themselves, e.g. placeholders in log formats
+    pub fn expand(&self, re: &str) -> Result<String> {
+        self.expand_nested(re, &mut Vec::new())
+    }
+
+    fn expand_nested<'p>(&'p self, re: &str, stack: &mut Vec<&'p str>) -> Result<String> {
+        let mut res = String::with_capacity(re.len());
+        let mut last = 0;
+        for caps in self.reference.captures_iter(re) {
+            // the whole match and the first group always participate
+            let (m, name) = (caps.get(0).unwrap(), caps.get(1).unwrap().as_str());
+            let (name, def) = match self.definitions.get_key_value(name) {
+                Some((name, def)) => (name.as_str(), def),
+                None => {
+                    res.push_str(&re[last..m.start()]);
+                    res.push_str(&regex::escape(m.as_str()));
+                    last = m.end();
+                    continue;
+                }
+            };
+            if stack.contains(&name) {
+                bail!("Recursive definition of pattern {}", name);
+            }
+
+            stack.push(name);
+            let expanded = self.expand_nested(def, stack)?;
+            stack.pop();
+
+            res.push_str(&re[last..m.start()]);
+            match caps.get(2) {
+                Some(capture) => res.push_str(&format!("(?P<{}>{})", capture.as_str(), expanded)),
+                None => res.push_str(&format!("(?:{})", expanded)),
+            }
+            last = m.end();
+        }
+        res.push_str(&re[last..]);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_patterns_compile() {
+        let patterns = Patterns::new();
+        for (name, _) in BUILTIN {
+            let re = patterns.expand(&format!("%{{{}}}", name)).unwrap();
+            assert!(regex::Regex::new(&re).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        let patterns = Patterns::new();
+        let re = patterns.expand("^%{IPV4:client} %{WORD}$").unwrap();
+        let caps = regex::Regex::new(&re)
+            .unwrap()
+            .captures("192.168.0.1 GET")
+            .unwrap();
+        assert_eq!(&caps["client"], "192.168.0.1");
+
+        let line = r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /a.gif HTTP/1.0" 200 2326 "-" "curl/7.1""#;
+        let re = patterns.expand("^%{NGINXACCESS}$").unwrap();
+        let caps = regex::Regex::new(&re).unwrap().captures(line).unwrap();
+        assert_eq!(&caps["verb"], "GET");
+        assert_eq!(&caps["agent"], r#""curl/7.1""#);
+
+        assert_eq!(patterns.expand("a%{2}").unwrap(), "a%{2}");
+        assert_eq!(
+            patterns.expand("%{user} %{WORD}").unwrap(),
+            format!(r"%\{{user\}} {}", patterns.expand("%{WORD}").unwrap())
+        );
+    }
+}
@@ -0,0 +1,540 @@
+// Splitting of the input into the records passed through the program
+use crate::engine::Regex;
+use crate::record::{self, Format};
+use crate::Result;
+use std::borrow::Cow;
+use std::fmt;
+use std::io::BufRead;
+use std::mem;
+
+// What separates the parts of the input
+#[derive(Debug)]
+pub enum Separator {
+    Newline,
+    Null,
+    Paragraph, // one or more blank lines
+    Regex(Regex),
+}
+
+// How the bytes of the input are checked before they are processed
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Encoding {
+    Utf8,  // invalid UTF-8 is an error
+    Lossy, // invalid UTF-8 is replaced by U+FFFD
+    Bytes, // any bytes are taken as they are
+}
+
+// A record of the input isn't valid UTF-8
+#[derive(Debug)]
+pub struct InvalidUtf8 {
+    pub line: usize, // containing the first invalid byte
+}
+
+impl fmt::Display for InvalidUtf8 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid UTF-8 in line {}", self.line)
+    }
+}
+
+impl std::error::Error for InvalidUtf8 {}
+
+// Line endings of records printed as text
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Eol {
+    Keep, // as in the input, including a missing final newline
+    Lf,
+    Crlf,
+}
+
+impl Eol {
+    // Maps the terminator of a record in the input to the one to print
+    pub fn apply(self, terminator: &'static str) -> &'static str {
+        match (self, terminator) {
+            (Eol::Keep, terminator) => terminator,
+            (_, "\0") => "\0",
+            (Eol::Lf, "\n\n" | "\r\n\r\n") => "\n\n",
+            (Eol::Crlf, "\n\n" | "\r\n\r\n") => "\r\n\r\n",
+            (Eol::Lf, _) => "\n",
+            (Eol::Crlf, _) => "\r\n",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub text: Vec<u8>,
+    pub line: usize, // 1-based number of the line the record starts at
+    // how the record ended in the input; empty if the input ended without
+    // a final newline or separator
+    pub terminator: &'static str,
+    // how its first line ended, which the line breaks in `text` stand for
+    pub line_break: &'static str,
+}
+
+// Yields every part of the input as a record. With a `start` regex, only
+// parts matching it start a new record, while all others are appended to
+// the current one on a new line, e.g. the lines of a stack trace to the log
+// message before. Line breaks in records are always `\n`, while the style
+// used by the input is given by `Record::line_break`. Records are checked
+// according to the encoding as a whole, so a record with an invalid part is
+// rejected along with all of its parts. With delimited formats, line breaks
+// within quoted values don't end a line.
+pub struct Records<'r, R> {
+    input: R,
+    separator: &'r Separator,
+    start: Option<&'r Regex>,
+    encoding: Encoding,
+    format: Format,
+    line: usize,              // at which the unread input starts
+    line_break: &'static str, // of the last line that ended with one
+    peeked: Option<Record>,   // part starting the next record
+    whole: Option<Vec<u8>>,   // the input, if read at once
+    offset: usize,            // of the unread input in `whole`
+}
+
+impl<'r, R: BufRead> Records<'r, R> {
+    pub fn new(
+        input: R,
+        separator: &'r Separator,
+        start: Option<&'r Regex>,
+        encoding: Encoding,
+        format: Format,
+    ) -> Self {
+        Records {
+            input,
+            separator,
+            start,
+            encoding,
+            format,
+            line: 1,
+            line_break: "\n",
+            peeked: None,
+            whole: None,
+            offset: 0,
+        }
+    }
+
+    // Reads a single part regardless of `start`, e.g. the header
+    pub fn next_part(&mut self) -> Result<Option<Record>> {
+        match self.read_part()? {
+            Some(mut part) => {
+                part.text = self.decode(part.text, part.line)?;
+                Ok(Some(part))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Like `next_part`, but leaves the part unchecked
+    fn read_part(&mut self) -> Result<Option<Record>> {
+        if let Some(part) = self.peeked.take() {
+            return Ok(Some(part));
+        }
+        match self.separator {
+            Separator::Newline => self.read_line(),
+            Separator::Null => self.read_until(b'\0'),
+            Separator::Paragraph => self.read_paragraph(),
+            Separator::Regex(regex) => self.read_to(regex),
+        }
+    }
+
+    // `line` is the number of the line `buf` starts at
+    fn decode(&self, buf: Vec<u8>, line: usize) -> Result<Vec<u8>> {
+        match self.encoding {
+            Encoding::Utf8 => match std::str::from_utf8(&buf) {
+                Ok(_) => Ok(buf),
+                Err(err) => {
+                    let valid = &buf[..err.valid_up_to()];
+                    let line = line + valid.iter().filter(|&&b| b == b'\n').count();
+                    Err(InvalidUtf8 { line }.into())
+                }
+            },
+            Encoding::Lossy => match String::from_utf8_lossy(&buf) {
+                Cow::Borrowed(_) => Ok(buf),
+                Cow::Owned(text) => Ok(text.into_bytes()),
+            },
+            Encoding::Bytes => Ok(buf),
+        }
+    }
+
+    // Like `BufRead::lines`, the `\r` of a `\r\n` is removed from lines
+    fn read_until(&mut self, delimiter: u8) -> Result<Option<Record>> {
+        let mut buf = Vec::new();
+        if self.input.read_until(delimiter, &mut buf)? == 0 {
+            return Ok(None);
+        }
+        let line = self.line;
+        self.line += buf.iter().filter(|&&b| b == b'\n').count();
+        let terminator = match buf.last() {
+            Some(b'\0') if delimiter == b'\0' => "\0",
+            Some(b'\n') if buf.ends_with(b"\r\n") => "\r\n",
+            Some(b'\n') => "\n",
+            _ => "",
+        };
+        buf.truncate(buf.len() - terminator.len());
+        // a line lacking its terminator is taken to be like the ones before
+        if terminator.ends_with('\n') {
+            self.line_break = terminator;
+        }
+        Ok(Some(Record {
+            text: buf,
+            line,
+            terminator,
+            line_break: self.line_break,
+        }))
+    }
+
+    // A quoted value missing its closing quote at the end of the input is
+    // left to be reported when the record is split
+    fn read_line(&mut self) -> Result<Option<Record>> {
+        let mut line = match self.read_until(b'\n')? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if let Format::Delimited(sep) = self.format {
+            while record::ends_quoted(&line.text, sep) {
+                match self.read_until(b'\n')? {
+                    Some(next) => {
+                        line.text.push(b'\n');
+                        line.text.extend_from_slice(&next.text);
+                        line.terminator = next.terminator;
+                    }
+                    None => break,
+                }
+            }
+        }
+        Ok(Some(line))
+    }
+
+    // Lines containing nothing but whitespace count as blank
+    fn read_paragraph(&mut self) -> Result<Option<Record>> {
+        let mut paragraph: Option<Record> = None;
+        while let Some(line) = self.read_until(b'\n')? {
+            if line.text.trim_ascii().is_empty() {
+                if paragraph.is_some() {
+                    break;
+                }
+            } else if let Some(paragraph) = &mut paragraph {
+                paragraph.text.push(b'\n');
+                paragraph.text.extend_from_slice(&line.text);
+            } else {
+                paragraph = Some(line);
+            }
+        }
+        // paragraphs are printed with a blank line after each
+        Ok(paragraph.map(|paragraph| Record {
+            terminator: match paragraph.line_break {
+                "\r\n" => "\r\n\r\n",
+                _ => "\n\n",
+            },
+            ..paragraph
+        }))
+    }
+
+    // The input is read at once, since separators may span any number of
+    // lines; empty matches don't separate anything. A final newline of the
+    // input doesn't belong to the last part.
+    fn read_to(&mut self, separator: &Regex) -> Result<Option<Record>> {
+        let whole = match &mut self.whole {
+            Some(whole) => whole,
+            None => {
+                let mut buf = Vec::new();
+                self.input.read_to_end(&mut buf)?;
+                self.whole.insert(buf)
+            }
+        };
+        let rest = &whole[self.offset..];
+        if rest.is_empty() {
+            return Ok(None);
+        }
+        let (mut end, terminator) = match rest.strip_suffix(b"\n") {
+            Some(last) => ((last.len(), rest.len()), "\n"),
+            None => ((rest.len(), rest.len()), ""),
+        };
+        for caps in separator.captures_iter(rest) {
+            // the whole match is always present
+            let m = caps?.get(0).unwrap().range();
+            if !m.is_empty() {
+                end = (m.start, m.end);
+                break;
+            }
+        }
+        let line = self.line;
+        self.line += rest[..end.1].iter().filter(|&&b| b == b'\n').count();
+        self.offset += end.1;
+        Ok(Some(Record {
+            text: rest[..end.0].to_vec(),
+            line,
+            // records are printed on lines of their own
+            terminator: if end.1 < rest.len() { "\n" } else { terminator },
+            line_break: "\n",
+        }))
+    }
+
+    fn next_record(&mut self) -> Result<Option<Record>> {
+        let start = match self.start {
+            Some(start) => start,
+            None => return self.next_part(),
+        };
+        let mut record = match self.read_part()? {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+        let mut text = self.decode(mem::take(&mut record.text), record.line);
+        while let Some(mut part) = self.read_part()? {
+            match self.decode(mem::take(&mut part.text), part.line) {
+                // an invalid part can't start a record
+                Ok(part_text) if start.is_match(&part_text)? => {
+                    self.peeked = Some(Record {
+                        text: part_text,
+                        ..part
+                    });
+                    break;
+                }
+                part_text => {
+                    // the first invalid part is reported
+                    text = match (text, part_text) {
+                        (Ok(mut text), Ok(part_text)) => {
+                            text.push(b'\n');
+                            text.extend_from_slice(&part_text);
+                            Ok(text)
+                        }
+                        (Err(err), _) | (_, Err(err)) => Err(err),
+                    };
+                    record.terminator = part.terminator;
+                }
+            }
+        }
+        record.text = text?;
+        Ok(Some(record))
+    }
+}
+
+impl<'r, R: BufRead> Iterator for Records<'r, R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // of an input with Unix line endings unless the terminator says otherwise
+    fn record(text: &str, line: usize, terminator: &'static str) -> Record {
+        Record {
+            text: text.into(),
+            line,
+            terminator,
+            line_break: if terminator.starts_with("\r\n") {
+                "\r\n"
+            } else {
+                "\n"
+            },
+        }
+    }
+
+    fn read(input: &str, separator: Separator, start: Option<&Regex>) -> Vec<Record> {
+        Records::new(
+            input.as_bytes(),
+            &separator,
+            start,
+            Encoding::Utf8,
+            Format::Plain,
+        )
+        .map(Result::unwrap)
+        .collect()
+    }
+
+    #[test]
+    fn test_record_start() {
+        let input = "header\nat start\n2021 first\n  at a\n  at b\n2022 second\n2023 third\n  at c";
+        let start = Regex::new(r"^\d{4} ").unwrap();
+        let mut records = Records::new(
+            input.as_bytes(),
+            &Separator::Newline,
+            Some(&start),
+            Encoding::Utf8,
+            Format::Plain,
+        );
+
+        assert_eq!(
+            records.next_part().unwrap(),
+            Some(record("header", 1, "\n"))
+        );
+        let records: Vec<_> = records.map(Result::unwrap).collect();
+        assert_eq!(
+            records,
+            vec![
+                record("at start", 2, "\n"),
+                record("2021 first\n  at a\n  at b", 3, "\n"),
+                record("2022 second", 6, "\n"),
+                record("2023 third\n  at c", 7, ""),
+            ]
+        );
+
+        let records = read(input, Separator::Newline, None);
+        assert_eq!(records.len(), 8);
+        assert_eq!(records[3], record("  at a", 4, "\n"));
+
+        // a record missing its final newline keeps the style of its lines
+        let records = read(
+            "2021 a\r\n  at x\r\n2022 b\r\n  at y",
+            Separator::Newline,
+            Some(&start),
+        );
+        assert_eq!(
+            records,
+            vec![
+                record("2021 a\n  at x", 1, "\r\n"),
+                Record {
+                    line_break: "\r\n",
+                    ..record("2022 b\n  at y", 3, "")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_separators() {
+        assert_eq!(
+            read("a\r\nb\n\nc\r", Separator::Newline, None),
+            vec![
+                record("a", 1, "\r\n"),
+                record("b", 2, "\n"),
+                record("", 3, "\n"),
+                record("c\r", 4, ""),
+            ]
+        );
+        assert_eq!(
+            read("new\nline\0b\0", Separator::Null, None),
+            vec![record("new\nline", 1, "\0"), record("b", 2, "\0")]
+        );
+        assert_eq!(
+            read("\n\na\r\nb\r\n \nc", Separator::Paragraph, None),
+            vec![record("a\nb", 3, "\r\n\r\n"), record("c", 6, "\n\n")]
+        );
+        assert_eq!(
+            read(
+                "a\n---\nb\nc\n---\nd\n\n---\n",
+                Separator::Regex(Regex::new("\n?---\n|x*").unwrap()),
+                None
+            ),
+            vec![
+                record("a", 1, "\n"),
+                record("b\nc", 3, "\n"),
+                record("d\n", 6, "\n")
+            ]
+        );
+        assert_eq!(
+            read("a;b\n", Separator::Regex(Regex::new(";").unwrap()), None),
+            vec![record("a", 1, "\n"), record("b", 1, "\n")]
+        );
+        assert_eq!(
+            read("a;b", Separator::Regex(Regex::new(";").unwrap()), None),
+            vec![record("a", 1, "\n"), record("b", 1, "")]
+        );
+    }
+
+    #[test]
+    fn test_quoted_line_breaks() {
+        let input = "a,\"b\r\nc\"\r\n\"d\"\"\ne\",f\n\"g\n";
+        let records: Vec<_> = Records::new(
+            input.as_bytes(),
+            &Separator::Newline,
+            None,
+            Encoding::Utf8,
+            Format::Delimited(','),
+        )
+        .map(Result::unwrap)
+        .collect();
+        assert_eq!(
+            records,
+            vec![
+                record("a,\"b\nc\"", 1, "\r\n"),
+                record("\"d\"\"\ne\",f", 3, "\n"),
+                record("\"g", 5, "\n"),
+            ]
+        );
+        assert_eq!(read(input, Separator::Newline, None).len(), 5);
+    }
+
+    #[test]
+    fn test_eol() {
+        let terminators = ["\n", "\r\n", "", "\0", "\n\n", "\r\n\r\n"];
+        let apply = |eol: Eol| -> Vec<_> { terminators.iter().map(|t| eol.apply(t)).collect() };
+        assert_eq!(apply(Eol::Keep), terminators);
+        assert_eq!(apply(Eol::Lf), ["\n", "\n", "\n", "\0", "\n\n", "\n\n"]);
+        assert_eq!(
+            apply(Eol::Crlf),
+            ["\r\n", "\r\n", "\r\n", "\0", "\r\n\r\n", "\r\n\r\n"]
+        );
+    }
+
+    #[test]
+    fn test_encodings() {
+        let input: &[u8] = b"caf\xe9\nok\nna\xefve\n";
+        let read = |input: &[u8], separator, start: Option<&Regex>, encoding| -> Vec<_> {
+            Records::new(input, &separator, start, encoding, Format::Plain)
+                .map(|record| match record {
+                    Ok(record) => Ok(record.text),
+                    Err(err) => Err(err.downcast::<InvalidUtf8>().unwrap().line),
+                })
+                .collect()
+        };
+
+        // reading goes on after invalid records
+        assert_eq!(
+            read(input, Separator::Newline, None, Encoding::Utf8),
+            vec![Err(1), Ok(b"ok".to_vec()), Err(3)]
+        );
+        assert_eq!(
+            read(
+                input,
+                Separator::Regex(Regex::new("\n").unwrap()),
+                None,
+                Encoding::Utf8
+            ),
+            vec![Err(1), Ok(b"ok".to_vec()), Err(3)]
+        );
+        assert_eq!(
+            read(input, Separator::Newline, None, Encoding::Lossy),
+            vec![
+                Ok("caf\u{fffd}".into()),
+                Ok(b"ok".to_vec()),
+                Ok("na\u{fffd}ve".into())
+            ]
+        );
+        assert_eq!(
+            read(input, Separator::Newline, None, Encoding::Bytes),
+            vec![
+                Ok(b"caf\xe9".to_vec()),
+                Ok(b"ok".to_vec()),
+                Ok(b"na\xefve".to_vec())
+            ]
+        );
+
+        // records with an invalid part are rejected as a whole
+        let start = Regex::new(r"^\d{4}").unwrap();
+        assert_eq!(
+            read(
+                b"2021 a\n  x\xff\n  y\n2022 b\n",
+                Separator::Newline,
+                Some(&start),
+                Encoding::Utf8
+            ),
+            vec![Err(2), Ok(b"2022 b".to_vec())]
+        );
+        assert_eq!(
+            read(
+                b"a\nb\xff\nc\n\nd\n",
+                Separator::Paragraph,
+                None,
+                Encoding::Utf8
+            ),
+            vec![Err(2), Ok(b"d".to_vec())]
+        );
+    }
+}
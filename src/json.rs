@@ -0,0 +1,66 @@
+use serde_json::{Map, Value};
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+// Path to a value inside a JSON document, e.g. `.request.method` or `.tags[0]`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Path(Vec<Segment>);
+
+impl Path {
+    pub fn new(segments: Vec<Segment>) -> Path {
+        Path(segments)
+    }
+
+    pub fn get<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        self.0
+            .iter()
+            .try_fold(value, |value, segment| match segment {
+                Segment::Key(key) => value.get(key),
+                Segment::Index(i) => value.get(i),
+            })
+    }
+
+    // Inserts `new` at the path, replacing everything in the way that is not
+    // an object or array, respectively; arrays are padded with `null`s.
+    pub fn insert(&self, root: &mut Value, new: Value) {
+        let mut current = root;
+        for segment in &self.0 {
+            current = match segment {
+                Segment::Key(key) => {
+                    if !current.is_object() {
+                        *current = Value::Object(Map::new());
+                    }
+                    // checked above, so safe to unwrap
+                    current
+                        .as_object_mut()
+                        .unwrap()
+                        .entry(key.clone())
+                        .or_insert(Value::Null)
+                }
+                Segment::Index(i) => {
+                    if !current.is_array() {
+                        *current = Value::Array(Vec::new());
+                    }
+                    let array = current.as_array_mut().unwrap();
+                    if array.len() <= *i {
+                        array.resize(i + 1, Value::Null);
+                    }
+                    &mut array[*i]
+                }
+            };
+        }
+        *current = new;
+    }
+}
+
+// Strings are used without quotes, everything else is serialized as JSON
+pub fn to_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
@@ -0,0 +1,77 @@
+// Parsing and formatting of logfmt records like `level=info msg="a b" ok`,
+// i.e. whitespace-separated keys with optional, possibly quoted values.
+
+pub type Pair = (String, Option<String>);
+
+pub fn parse(record: &str) -> Vec<Pair> {
+    let mut pairs = Vec::new();
+    let mut chars = record.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '=') {
+            key.push(c);
+        }
+        if key.is_empty() && chars.peek().is_none() {
+            return pairs;
+        }
+        if chars.next_if_eq(&'=').is_none() {
+            pairs.push((key, None));
+            continue;
+        }
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => match chars.next() {
+                        Some('n') => value.push('\n'),
+                        Some('t') => value.push('\t'),
+                        Some('r') => value.push('\r'),
+                        Some(c) => value.push(c),
+                        None => value.push('\\'),
+                    },
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                value.push(c);
+            }
+        }
+        pairs.push((key, Some(value)));
+    }
+}
+
+pub fn join(pairs: &[&Pair]) -> String {
+    pairs
+        .iter()
+        .map(|(key, value)| match value {
+            Some(value) => format!("{}={}", key, quote(value)),
+            None => key.clone(),
+        })
+        .intersperse(" ".to_owned())
+        .collect()
+}
+
+fn quote(value: &str) -> String {
+    if value.contains(|c: char| c.is_whitespace() || "\"=\\".contains(c)) {
+        let mut res = String::from("\"");
+        for c in value.chars() {
+            match c {
+                '"' => res.push_str("\\\""),
+                '\\' => res.push_str("\\\\"),
+                '\n' => res.push_str("\\n"),
+                '\t' => res.push_str("\\t"),
+                '\r' => res.push_str("\\r"),
+                c => res.push(c),
+            }
+        }
+        res.push('"');
+        res
+    } else {
+        value.to_owned()
+    }
+}
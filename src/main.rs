@@ -1,22 +1,200 @@
 #![feature(iter_intersperse)]
 
+mod engine;
+mod exec;
+mod grok;
+mod input;
+mod json;
+mod logfmt;
+mod output;
 mod parse;
 mod program;
+mod record;
+mod replacement;
+mod table;
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use clap::{App, AppSettings, Arg};
+use engine::{Engine, Regex};
+use input::{Encoding, Eol, InvalidUtf8, Record, Records, Separator};
+use output::{Mode, Printer};
+use program::{InvalidText, LineError, Meta, Track, Violation};
+use record::{Format, InvalidJson};
 use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, IsTerminal};
+use std::process;
+use std::time::Duration;
 
-fn main() -> Result<()> {
+// Like grep, exits with 0 if anything was found, 1 if not and 2 on errors
+fn main() {
+    process::exit(match run() {
+        Ok(status) => status,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            2
+        }
+    })
+}
+
+fn run() -> Result<i32> {
     let matches = App::new("saw")
         .arg(
             Arg::with_name("file")
                 .short("f")
                 .long("file")
                 .value_name("FILE")
-                .help("Input file")
-                .takes_value(true),
+                .help("Input file; may be given multiple times")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("patterns")
+                .short("p")
+                .long("patterns")
+                .value_name("FILE")
+                .help("Load additional named patterns from FILE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("csv")
+                .long("csv")
+                .help("Split fields as comma-separated values")
+                .conflicts_with("tsv"),
+        )
+        .arg(
+            Arg::with_name("tsv")
+                .long("tsv")
+                .help("Split fields as tab-separated values"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Parse each line as JSON document")
+                .conflicts_with_all(&["csv", "tsv", "header", "keep-header"]),
+        )
+        .arg(
+            Arg::with_name("logfmt")
+                .long("logfmt")
+                .help("Parse each line as logfmt key=value pairs")
+                .conflicts_with_all(&["csv", "tsv", "json", "header", "keep-header"]),
+        )
+        .arg(
+            Arg::with_name("header")
+                .short("H")
+                .long("header")
+                .help("Use the first line as header naming the fields"),
+        )
+        .arg(
+            Arg::with_name("keep-header")
+                .long("keep-header")
+                .help("Like --header, but also print the header"),
+        )
+        .arg(
+            Arg::with_name("null")
+                .short("z")
+                .long("null")
+                .help("Separate input and output records by NUL bytes instead of newlines"),
+        )
+        .arg(
+            Arg::with_name("rs")
+                .long("rs")
+                .value_name("REGEX")
+                .help("Separate input records by matches of REGEX instead of newlines")
+                .takes_value(true)
+                .conflicts_with("null"),
+        )
+        .arg(
+            Arg::with_name("paragraph")
+                .long("paragraph")
+                .help("Separate records by blank lines instead of newlines")
+                .conflicts_with_all(&["null", "rs"]),
+        )
+        .arg(
+            Arg::with_name("eol")
+                .long("eol")
+                .value_name("EOL")
+                .help("Line endings of printed records [default: keep those of the input]")
+                .possible_values(&["lf", "crlf", "keep"])
+                .conflicts_with("null"),
+        )
+        .arg(
+            Arg::with_name("bytes")
+                .long("bytes")
+                .help("Process the input as bytes, which need not be valid UTF-8"),
+        )
+        .arg(
+            Arg::with_name("invalid-utf8")
+                .long("invalid-utf8")
+                .value_name("ACTION")
+                .help("Fail on, replace or skip with a warning records with invalid UTF-8 [default: error]")
+                .possible_values(&["error", "lossy", "skip"])
+                .conflicts_with("bytes"),
+        )
+        .arg(
+            Arg::with_name("record-start")
+                .long("record-start")
+                .value_name("REGEX")
+                .help("Only start a new record on lines matching REGEX")
+                .takes_value(true)
+                .conflicts_with_all(&["null", "rs", "paragraph"]),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FORMAT")
+                .help("Print results as plain text, as JSON objects or as path:line:column:text")
+                .possible_values(&["text", "json", "jsonl", "quickfix"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("color")
+                .long("color")
+                .value_name("WHEN")
+                .help("Highlight matched and inserted text")
+                .possible_values(&["auto", "always", "never"])
+                .default_value("auto"),
+        )
+        .arg(
+            Arg::with_name("engine")
+                .long("engine")
+                .value_name("ENGINE")
+                .help("Regex engine used unless a regex starts with (*REGEX) or (*FANCY)")
+                .possible_values(&["regex", "fancy"]),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("Abort if a command run by exec takes longer")
+                .takes_value(true)
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(secs) if secs > 0.0 && secs.is_finite() => Ok(()),
+                    _ => Err(format!("Not a positive number of seconds: {}", s)),
+                }),
+        )
+        .arg(
+            Arg::with_name("count")
+                .short("c")
+                .long("count")
+                .help("Only print the number of results per file"),
+        )
+        .arg(
+            Arg::with_name("files-with-matches")
+                .short("l")
+                .long("files-with-matches")
+                .help("Only print the paths of files with results")
+                .conflicts_with("count"),
+        )
+        .arg(
+            Arg::with_name("files-without-match")
+                .short("L")
+                .long("files-without-match")
+                .help("Only print the paths of files without results")
+                .conflicts_with_all(&["count", "files-with-matches"]),
         )
         .setting(AppSettings::TrailingVarArg)
         .arg(
@@ -25,24 +203,291 @@ fn main() -> Result<()> {
                 .required(true)
                 .multiple(true),
         )
-        .get_matches();
-
-    let input: Box<dyn BufRead> = match matches.value_of("file") {
-        Some(path) => Box::new(BufReader::new(File::open(path)?)),
-        None => Box::new(BufReader::new(io::stdin())),
-    };
+        .get_matches_safe()
+        .unwrap_or_else(|err| {
+            // help and version are printed to stdout and are no errors
+            if !err.use_stderr() {
+                err.exit()
+            }
+            eprintln!("{}", err.message);
+            process::exit(2)
+        });
 
     // required argument, so safe to unwrap
     let commands: Vec<_> = matches.values_of("prog").unwrap().collect();
-    let mut program = parse::parse_args(&commands)?;
-    input
-        .lines()
-        .map(|line| {
-            match program.run(line?) {
-                Some(res) => println!("{}", res),
-                _ => (),
-            };
+    let mut patterns = grok::Patterns::new();
+    for path in matches.values_of("patterns").into_iter().flatten() {
+        patterns.load(path)?;
+    }
+
+    let engine = match matches.value_of("engine") {
+        Some("regex") => Engine::Regex,
+        Some("fancy") => Engine::Fancy,
+        _ => Engine::default(),
+    };
+    let regex_of = |arg| -> Result<Option<Regex>> {
+        match matches.value_of(arg) {
+            Some(re) => Ok(Some(
+                patterns
+                    .expand(re)
+                    .and_then(|re| Regex::with_engine(&re, engine))
+                    .with_context(|| format!("Invalid argument of --{}", arg))?,
+            )),
+            None => Ok(None),
+        }
+    };
+    let record_start = regex_of("record-start")?;
+    let (separator, terminator) = if matches.is_present("null") {
+        (Separator::Null, "\0")
+    } else if matches.is_present("paragraph") {
+        (Separator::Paragraph, "\n\n")
+    } else if let Some(regex) = regex_of("rs")? {
+        (Separator::Regex(regex), "\n")
+    } else {
+        (Separator::Newline, "\n")
+    };
+
+    let keep_header = matches.is_present("keep-header");
+    let header = matches.is_present("header") || keep_header;
+    let options = parse::Options {
+        patterns,
+        engine,
+        header,
+        // validated above, so safe to unwrap
+        timeout: matches
+            .value_of("timeout")
+            .map(|secs| Duration::from_secs_f64(secs.parse().unwrap())),
+        format: if matches.is_present("csv") {
+            Format::Delimited(',')
+        } else if matches.is_present("tsv") {
+            Format::Delimited('\t')
+        } else if matches.is_present("json") {
+            Format::Json
+        } else if matches.is_present("logfmt") {
+            Format::Logfmt
+        } else {
+            Format::Plain
+        },
+    };
+    let mut program = parse::parse_args(&commands, &options)?;
+
+    let mode = if matches.is_present("count") {
+        Mode::Count
+    } else if matches.is_present("files-with-matches") {
+        Mode::FilesWithMatches
+    } else if matches.is_present("files-without-match") {
+        Mode::FilesWithoutMatch
+    } else {
+        match matches.value_of("output") {
+            Some("json") => Mode::Json,
+            Some("jsonl") => Mode::JsonLines,
+            Some("quickfix") => Mode::Quickfix,
+            _ => Mode::Text,
+        }
+    };
+    let color = match matches.value_of("color") {
+        Some("always") => true,
+        Some("never") => false,
+        _ => io::stdout().is_terminal(),
+    };
+    let eol = match matches.value_of("eol") {
+        Some("lf") => Eol::Lf,
+        Some("crlf") => Eol::Crlf,
+        _ => Eol::Keep,
+    };
+    let skip_invalid = matches.value_of("invalid-utf8") == Some("skip");
+    let encoding = if matches.is_present("bytes") {
+        Encoding::Bytes
+    } else if matches.value_of("invalid-utf8") == Some("lossy") {
+        Encoding::Lossy
+    } else {
+        Encoding::Utf8
+    };
+    let files: Vec<_> = match matches.values_of("file") {
+        Some(paths) => paths.map(Some).collect(),
+        None => vec![None],
+    };
+    // looking up groups and match positions is only worth it if they are used
+    let track = Track {
+        captures: matches!(mode, Mode::Json | Mode::JsonLines) || program.uses_captures(),
+        positions: mode == Mode::Quickfix || (color && mode == Mode::Text),
+    };
+    let separator_out = eol.apply(terminator);
+    let mut printer = Printer::new(io::stdout(), mode, color, files.len() > 1, separator_out);
+
+    // files that can't be opened and failed checks are reported, but don't
+    // stop the run
+    let mut failed = false;
+    for file in files {
+        let input: Box<dyn BufRead> = match file {
+            Some(path) => match File::open(path) {
+                Ok(file) => Box::new(BufReader::new(file)),
+                Err(err) => {
+                    eprintln!("Error: Could not open {}: {}", path, err);
+                    failed = true;
+                    continue;
+                }
+            },
+            None => Box::new(BufReader::new(io::stdin())),
+        };
+        // every file is processed on its own
+        program.reset();
+        printer.begin(file);
+
+        let mut records = Records::new(
+            input,
+            &separator,
+            record_start.as_ref(),
+            encoding,
+            options.format,
+        );
+        // an invalid header is skipped like any record, leaving none
+        let head = if header {
+            records.next_part().transpose()
+        } else {
+            None
+        };
+        let mut last = 0;
+        head.map_or(Ok(None), |record| {
+            valid(file, record, skip_invalid, "header")
+        })
+        .and_then(|record| {
+            if let Some(record) = record {
+                let line = program.header(record.text)?;
+                if keep_header {
+                    printer
+                        .set_terminator(eol.apply(record.terminator), eol.apply(record.line_break));
+                    printer.header(record.line, &line)?;
+                }
+            }
             Ok(())
         })
-        .collect()
+        .and_then(|_| {
+            records.try_for_each(|record| {
+                let record = match valid(file, record, skip_invalid, "record")? {
+                    Some(record) => record,
+                    None => return Ok(()),
+                };
+                last = record.line;
+                let line_break = eol.apply(record.line_break);
+                let mut meta = Meta {
+                    line: record.line,
+                    track,
+                    line_break,
+                    // records written to files are all terminated
+                    terminator: match eol.apply(record.terminator) {
+                        "" if separator_out == "\n" => line_break,
+                        "" => separator_out,
+                        terminator => terminator,
+                    },
+                    ..Meta::default()
+                };
+                let res = match program.run(record.text, &mut meta) {
+                    Ok(res) => res,
+                    // a single malformed line of JSON, or a line that isn't
+                    // text in byte mode, shouldn't end the run
+                    Err(err) if err.is::<InvalidJson>() || err.is::<InvalidText>() => {
+                        warn(file, record.line, &format!("Skipped record, {}", err));
+                        return Ok(());
+                    }
+                    Err(err) => return Err(err),
+                };
+                failed |= report(file, &meta.violations);
+                printer.set_terminator(eol.apply(record.terminator), line_break);
+                print(&mut printer, res.as_deref(), &meta)
+            })
+        })
+        .and_then(|_| {
+            let mut meta = Meta {
+                line: last,
+                track,
+                ended: true,
+                ..Meta::default()
+            };
+            program.finish(&mut meta)?;
+            failed |= report(file, &meta.violations);
+            print(&mut printer, None, &meta)
+        })
+        // report errors for an input and invalid input like compilers do
+        .map_err(|err| {
+            let path = file.unwrap_or("<stdin>");
+            match err.downcast::<LineError>() {
+                Ok(e) => anyhow!("{}:{}: {}", path, e.line, e.message),
+                Err(err) => match err.downcast::<InvalidUtf8>() {
+                    Ok(invalid) => anyhow!(
+                        "{}:{}: Invalid UTF-8, see --bytes and --invalid-utf8",
+                        path,
+                        invalid.line
+                    ),
+                    Err(err) => err,
+                },
+            }
+        })?;
+        printer.end()?;
+    }
+    printer.finish()?;
+    Ok(match (failed, printer.success()) {
+        (true, _) => 2,
+        (false, true) => 0,
+        (false, false) => 1,
+    })
+}
+
+// Reports failed checks like compilers do, returning whether there were any
+fn report(file: Option<&str>, violations: &[Violation]) -> bool {
+    for v in violations {
+        eprintln!(
+            "Error: {}:{}: {}",
+            file.unwrap_or("<stdin>"),
+            v.line,
+            v.message
+        );
+    }
+    !violations.is_empty()
+}
+
+// Passes on a valid record, or `None` for an invalid one that is skipped
+// rather than failing
+fn valid(
+    file: Option<&str>,
+    record: Result<Record>,
+    skip_invalid: bool,
+    what: &str,
+) -> Result<Option<Record>> {
+    match record {
+        Ok(record) => Ok(Some(record)),
+        Err(err) => match err.downcast::<InvalidUtf8>() {
+            Ok(invalid) if skip_invalid => {
+                let message = format!("Skipped {} with invalid UTF-8", what);
+                warn(file, invalid.line, &message);
+                Ok(None)
+            }
+            Ok(invalid) => Err(invalid.into()),
+            Err(err) => Err(err),
+        },
+    }
+}
+
+fn warn(file: Option<&str>, line: usize, message: &str) {
+    eprintln!(
+        "Warning: {}:{}: {}",
+        file.unwrap_or("<stdin>"),
+        line,
+        message
+    );
+}
+
+// Prints the result of an input along with the records emitted before it and
+// the lines appended to it
+fn print(printer: &mut Printer<io::Stdout>, res: Option<&[u8]>, meta: &Meta) -> Result<()> {
+    for (res, meta) in &meta.emitted {
+        print(printer, res.as_deref(), meta)?;
+    }
+    if let Some(res) = res {
+        printer.print(meta.line, res, meta)?;
+    }
+    for line in &meta.appended {
+        printer.append(meta.line, line)?;
+    }
+    Ok(())
 }
@@ -0,0 +1,385 @@
+// Printing of the inputs that make it through the program, either as plain
+// text or in formats that also carry where they came from.
+use crate::program::{Highlight, Meta};
+use crate::Result;
+use serde_json::{json, Map, Value};
+use std::io::Write;
+
+// Path used for standard input
+const STDIN: &str = "<stdin>";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mode {
+    Text,
+    Json,      // a single array of objects
+    JsonLines, // one object per line
+    Quickfix,  // `path:line:column:text` as understood by editors
+    // the following only print a summary per input file
+    Count,
+    FilesWithMatches,
+    FilesWithoutMatch,
+}
+
+pub struct Printer<W: Write> {
+    out: W,
+    mode: Mode,
+    color: bool,     // highlight spans of the text with ANSI escape sequences
+    with_path: bool, // prefix counts and text with the path of the file
+    // of the records printed as text next, which may be empty for the last
+    // one of an input missing its final newline
+    terminator: &'static str,
+    line_break: &'static str, // how line breaks in their text are written
+    separator: &'static str,  // written after records lacking a terminator
+    unterminated: Option<&'static str>, // separator owed by the last record
+    file: Option<String>,
+    printed: usize, // in total, including headers
+    found: usize,   // in total
+    in_file: usize, // in the current file
+    listed: usize,  // number of files listed
+}
+
+impl<W: Write> Printer<W> {
+    pub fn new(
+        out: W,
+        mode: Mode,
+        color: bool,
+        with_path: bool,
+        separator: &'static str,
+    ) -> Printer<W> {
+        Printer {
+            out,
+            mode,
+            color,
+            with_path,
+            terminator: separator,
+            line_break: "\n",
+            separator,
+            unterminated: None,
+            file: None,
+            printed: 0,
+            found: 0,
+            in_file: 0,
+            listed: 0,
+        }
+    }
+
+    // Starts a new input file; `None` stands for standard input
+    pub fn begin(&mut self, file: Option<&str>) {
+        self.file = file.map(From::from);
+        self.in_file = 0;
+    }
+
+    pub fn end(&mut self) -> Result<()> {
+        let path = self.file.as_deref().unwrap_or(STDIN);
+        match self.mode {
+            Mode::Count if self.with_path => writeln!(self.out, "{}:{}", path, self.in_file)?,
+            Mode::Count => writeln!(self.out, "{}", self.in_file)?,
+            Mode::FilesWithMatches if self.in_file > 0 => {
+                writeln!(self.out, "{}", path)?;
+                self.listed += 1;
+            }
+            Mode::FilesWithoutMatch if self.in_file == 0 => {
+                writeln!(self.out, "{}", path)?;
+                self.listed += 1;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Whether anything was found, as reported by the exit status
+    pub fn success(&self) -> bool {
+        match self.mode {
+            Mode::FilesWithoutMatch => self.listed > 0,
+            _ => self.found > 0,
+        }
+    }
+
+    pub fn set_terminator(&mut self, terminator: &'static str, line_break: &'static str) {
+        self.terminator = terminator;
+        self.line_break = line_break;
+    }
+
+    // `line` is the 1-based number of the input line `text` originates from
+    pub fn print(&mut self, line: usize, text: &[u8], meta: &Meta) -> Result<()> {
+        self.found += 1;
+        self.in_file += 1;
+        self.write(line, text, meta)
+    }
+
+    // Headers are printed like other lines, but not counted as found
+    pub fn header(&mut self, line: usize, text: &[u8]) -> Result<()> {
+        self.write(line, text, &Meta::default())
+    }
+
+    // Neither are lines appended to an input, e.g. by `read-file`
+    pub fn append(&mut self, line: usize, text: &[u8]) -> Result<()> {
+        self.write(line, text, &Meta::default())
+    }
+
+    fn write(&mut self, line: usize, text: &[u8], meta: &Meta) -> Result<()> {
+        let highlighted;
+        let text = if self.color && matches!(self.mode, Mode::Text | Mode::Quickfix) {
+            highlighted = highlight(text, meta);
+            &highlighted
+        } else {
+            text
+        };
+        match self.mode {
+            Mode::Text => {
+                // a missing terminator is only kept if nothing follows, and
+                // otherwise replaced by a line break like the ones before
+                if let Some(separator) = self.unterminated.take() {
+                    write!(self.out, "{}", separator)?;
+                }
+                // like grep, every line is attributed to its file, while
+                // newlines in NUL-separated records are just data
+                let prefixed;
+                let text = if self.with_path {
+                    let prefix = format!("{}:", self.file.as_deref().unwrap_or(STDIN));
+                    prefixed = match self.separator {
+                        "\0" => [prefix.as_bytes(), text].concat(),
+                        _ => {
+                            let line_break = format!("\n{}", prefix);
+                            [prefix.as_bytes(), &break_lines(text, line_break.as_bytes())].concat()
+                        }
+                    };
+                    &prefixed
+                } else {
+                    text
+                };
+                if self.line_break == "\r\n" {
+                    self.out.write_all(&break_lines(text, b"\r\n"))?;
+                } else {
+                    self.out.write_all(text)?;
+                }
+                write!(self.out, "{}", self.terminator)?;
+                if self.terminator.is_empty() {
+                    self.unterminated = Some(match self.separator {
+                        "\n" => self.line_break,
+                        separator => separator,
+                    });
+                }
+            }
+            Mode::Json => {
+                let sep = if self.printed == 0 { "[" } else { "," };
+                writeln!(self.out, "{}", sep)?;
+                write!(self.out, "  {}", self.to_json(line, text, meta))?;
+            }
+            Mode::JsonLines => writeln!(self.out, "{}", self.to_json(line, text, meta))?,
+            Mode::Quickfix => {
+                let path = self.file.as_deref().unwrap_or(STDIN);
+                let column = meta.column.unwrap_or(1);
+                let prefix = format!("{}:{}:{}:", path, line, column);
+                for text in text.split(|&b| b == b'\n') {
+                    write!(self.out, "{}", prefix)?;
+                    self.out.write_all(text)?;
+                    writeln!(self.out)?;
+                }
+            }
+            Mode::Count | Mode::FilesWithMatches | Mode::FilesWithoutMatch => (),
+        }
+        self.printed += 1;
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<()> {
+        if self.mode == Mode::Json {
+            if self.printed == 0 {
+                writeln!(self.out, "[]")?;
+            } else {
+                writeln!(self.out, "\n]")?;
+            }
+        }
+        Ok(self.out.flush()?)
+    }
+
+    // JSON can only carry text, so invalid UTF-8 is replaced by U+FFFD
+    fn to_json(&self, line: usize, text: &[u8], meta: &Meta) -> Value {
+        let captures: Map<_, _> = meta
+            .captures
+            .iter()
+            .map(|c| {
+                let value = String::from_utf8_lossy(&c.value);
+                (c.name.clone(), Value::String(value.into_owned()))
+            })
+            .collect();
+        json!({
+            "file": self.file,
+            "line": line,
+            "text": String::from_utf8_lossy(text),
+            "captures": captures,
+        })
+    }
+}
+
+// Replaces every `\n` of the text by `by`
+fn break_lines(text: &[u8], by: &[u8]) -> Vec<u8> {
+    text.split(|&b| b == b'\n').collect::<Vec<_>>().join(by)
+}
+
+// Wraps the spans of `text` in ANSI escape sequences; spans overlapping a
+// previous one are skipped
+fn highlight(text: &[u8], meta: &Meta) -> Vec<u8> {
+    let mut spans: Vec<_> = meta.spans.iter().collect();
+    spans.sort_by_key(|(span, _)| span.start);
+
+    let mut res = Vec::with_capacity(text.len());
+    let mut last = 0;
+    for (span, kind) in spans {
+        let part = match text.get(span.clone()) {
+            Some(part) if span.start >= last && !part.is_empty() => part,
+            _ => continue,
+        };
+        let color: &[u8] = match kind {
+            Highlight::Match => b"\x1b[1;31m",
+            Highlight::Insertion => b"\x1b[32m",
+        };
+        res.extend_from_slice(&text[last..span.start]);
+        // end the highlighting at line breaks so that lines can be split
+        let part: Vec<_> = part
+            .split(|&b| b == b'\n')
+            .map(|line| [color, line, b"\x1b[0m"].concat())
+            .collect();
+        res.extend(part.join(&b'\n'));
+        last = span.end;
+    }
+    res.extend_from_slice(&text[last..]);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Capture;
+
+    fn print_all(mode: Mode, lines: &[(usize, &str, Meta)]) -> String {
+        let mut printer = Printer::new(Vec::new(), mode, false, false, "\n");
+        printer.begin(Some("in.txt"));
+        for (line, text, meta) in lines {
+            printer.print(*line, text.as_bytes(), meta).unwrap();
+        }
+        printer.end().unwrap();
+        printer.finish().unwrap();
+        String::from_utf8(printer.out).unwrap()
+    }
+
+    #[test]
+    fn test_output_modes() {
+        let meta = Meta {
+            captures: vec![Capture {
+                name: "key".to_owned(),
+                value: b"a".to_vec(),
+                range: Some(0..1),
+            }],
+            column: Some(3),
+            ..Meta::default()
+        };
+        let lines = [(2, "a = 1", meta), (5, "b\nc", Meta::default())];
+
+        assert_eq!(
+            print_all(Mode::JsonLines, &lines),
+            concat!(
+                r#"{"file":"in.txt","line":2,"text":"a = 1","captures":{"key":"a"}}"#,
+                "\n",
+                r#"{"file":"in.txt","line":5,"text":"b\nc","captures":{}}"#,
+                "\n"
+            )
+        );
+        assert_eq!(
+            print_all(Mode::Json, &lines),
+            concat!(
+                "[\n  ",
+                r#"{"file":"in.txt","line":2,"text":"a = 1","captures":{"key":"a"}}"#,
+                ",\n  ",
+                r#"{"file":"in.txt","line":5,"text":"b\nc","captures":{}}"#,
+                "\n]\n"
+            )
+        );
+        assert_eq!(print_all(Mode::Json, &[]), "[]\n");
+        assert_eq!(print_all(Mode::Text, &lines), "a = 1\nb\nc\n");
+        assert_eq!(
+            print_all(Mode::Quickfix, &lines),
+            "in.txt:2:3:a = 1\nin.txt:5:1:b\nin.txt:5:1:c\n"
+        );
+    }
+
+    #[test]
+    fn test_highlight() {
+        let meta = Meta {
+            spans: vec![
+                (4..5, Highlight::Insertion),
+                (0..3, Highlight::Match),
+                (1..2, Highlight::Match),
+                (6..9, Highlight::Match),
+            ],
+            ..Meta::default()
+        };
+        assert_eq!(
+            highlight(b"abc d e\nf", &meta),
+            b"\x1b[1;31mabc\x1b[0m \x1b[32md\x1b[0m \x1b[1;31me\x1b[0m\n\x1b[1;31mf\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_summary_modes() {
+        let summarize = |mode| {
+            let mut printer = Printer::new(Vec::new(), mode, false, true, "\n");
+            for (file, n) in [("a.txt", 2), ("b.txt", 0)] {
+                printer.begin(Some(file));
+                for line in 1..=n {
+                    printer.print(line, b"text", &Meta::default()).unwrap();
+                }
+                printer.end().unwrap();
+            }
+            printer.finish().unwrap();
+            let success = printer.success();
+            (String::from_utf8(printer.out).unwrap(), success)
+        };
+
+        assert_eq!(
+            summarize(Mode::Count),
+            ("a.txt:2\nb.txt:0\n".to_owned(), true)
+        );
+        assert_eq!(
+            summarize(Mode::FilesWithMatches),
+            ("a.txt\n".to_owned(), true)
+        );
+        assert_eq!(
+            summarize(Mode::FilesWithoutMatch),
+            ("b.txt\n".to_owned(), true)
+        );
+        assert_eq!(
+            summarize(Mode::Text),
+            ("a.txt:text\na.txt:text\n".to_owned(), true)
+        );
+    }
+
+    #[test]
+    fn test_terminators() {
+        let mut printer = Printer::new(Vec::new(), Mode::Text, false, false, "\n");
+        let records = [
+            ("a", "\r\n", "\r\n"),
+            ("b\nc", "\r\n", "\r\n"),
+            ("d\ne", "", "\r\n"),
+            ("f", "", "\n"),
+            ("g", "", "\n"),
+        ];
+        for (text, terminator, line_break) in records {
+            printer.set_terminator(terminator, line_break);
+            printer.print(1, text.as_bytes(), &Meta::default()).unwrap();
+        }
+        printer.finish().unwrap();
+        // missing terminators are replaced by line breaks of the same style
+        assert_eq!(
+            String::from_utf8(printer.out).unwrap(),
+            "a\r\nb\r\nc\r\nd\r\ne\r\nf\ng"
+        );
+
+        let mut printer = Printer::new(Vec::new(), Mode::Text, false, true, "\0");
+        printer.begin(Some("n1"));
+        printer.set_terminator("\0", "\n");
+        printer.print(1, b"a\nb", &Meta::default()).unwrap();
+        assert_eq!(printer.out, b"n1:a\nb\0");
+    }
+}
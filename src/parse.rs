@@ -1,24 +1,33 @@
+use crate::engine::{Engine, Regex};
+use crate::grok::Patterns;
+use crate::json::{Path, Segment};
 use crate::program::{self, Atom, Program};
+use crate::record::Format;
+use crate::replacement::{Case, GroupRef, Piece, Replacement};
+use crate::table;
 use crate::Result;
 
 use nom::{
     self,
     branch::alt,
-    character::complete::{char, digit1},
-    combinator::{all_consuming, cut, map_res, opt, success, verify},
+    bytes::complete::{tag, take_while, take_while1, take_while_m_n},
+    character::complete::{anychar, char, digit1, one_of},
+    combinator::{all_consuming, cut, map_opt, map_res, opt, success, value, verify},
     error::{ErrorKind, FromExternalError},
-    multi::{many0, separated_list0},
+    multi::{fold_many0, many0, separated_list0},
     sequence::{delimited, preceded, separated_pair},
     Finish, Parser,
 };
-use regex::Regex;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Display};
+use std::time::Duration;
 
 type IResult<I, O> = nom::IResult<I, O, ParseError>;
 
 #[derive(Debug)]
 enum ParseError {
     Message(anyhow::Error), // Error with a user directed message
+    UnknownKeyword(String), // An argument that doesn't start an atom
     Internal,               // An internal error, e.g. the failure of a parser
 }
 impl ParseError {
@@ -54,6 +63,9 @@ impl From<ParseError> for anyhow::Error {
     fn from(source: ParseError) -> Self {
         match source {
             ParseError::Message(err) => err,
+            ParseError::UnknownKeyword(arg) => {
+                anyhow::Error::msg(format!("Not a recognized keyword: {}", arg))
+            }
             ParseError::Internal => anyhow::Error::msg("Internal error"),
         }
     }
@@ -73,6 +85,7 @@ impl Context for ParseError {
         use ParseError::*;
         match self {
             Message(err) => Message(err.context(message)),
+            err @ UnknownKeyword(_) => Message(anyhow::Error::from(err).context(message)),
             Internal => Message(anyhow::Error::msg(format!("{}", message))),
         }
     }
@@ -105,7 +118,43 @@ where
 
 type Args<'i> = &'i [&'i str];
 
-pub fn parse_args(input: Args) -> Result<Program> {
+// Settings affecting the construction of atoms
+#[derive(Debug)]
+pub struct Options {
+    pub format: Format,
+    pub patterns: Patterns,
+    pub engine: Engine,            // unless chosen by a regex itself
+    pub header: bool,              // whether input files start with a header
+    pub timeout: Option<Duration>, // of commands run by `exec`
+}
+
+pub fn parse_args(input: Args, options: &Options) -> Result<Program> {
+    let (rest, program) = program(input, options).finish()?;
+    match rest.first() {
+        Some(arg) => Err(anyhow::Error::msg(format!("Unexpected keyword: {}", arg))),
+        None => Ok(program),
+    }
+}
+
+// Parses atoms until the input is exhausted or an 'end' keyword is reached
+fn program<'i>(input: Args<'i>, options: &Options) -> IResult<Args<'i>, Program> {
+    many0(|i| atom(i, options)).map(Program::new).parse(input)
+}
+
+fn atom<'i>(input: Args<'i>, options: &Options) -> IResult<Args<'i>, Atom> {
+    let format = options.format;
+    let regex = |s| regex(s, options);
+    let field_id = |s| field_id(s, options);
+    let field_list = |s| all_consuming(separated_list0(char(','), |s| fields_atom(s, options)))(s);
+    let selector = |s| selector(s, options);
+    // for optional arguments, which can't be mistaken for a following atom
+    let not_atom = |arg: &str| {
+        matches!(
+            atom(&[arg], options),
+            Err(nom::Err::Failure(ParseError::UnknownKeyword(_)))
+        )
+    };
+
     let enumeration = command(
         &["enumerate", "enum", "e", "#"],
         success(())
@@ -130,16 +179,50 @@ pub fn parse_args(input: Args) -> Result<Program> {
     let sub = command(
         &["sub", "s"],
         next.and_then(arg(regex))
-            .and(next.map(|s: &str| s.to_string()))
-            .map(|(regex, s)| program::Sub::new(regex, s))
+            .and(next.and_then(arg(replacement)))
+            .map(|(regex, s)| program::Sub::new(regex, s, false))
             .map(From::from),
     );
 
     let gsub = command(
         &["gsub", "gs"],
         next.and_then(arg(regex))
-            .and(next.map(From::from))
-            .map(|(regex, s)| program::Gsub::new(regex, s))
+            .and(next.and_then(arg(replacement)))
+            .map(|(regex, s)| program::Gsub::new(regex, s, false))
+            .map(From::from),
+    );
+
+    let sub_nth = command(
+        &["sub-nth", "sn"],
+        next.and_then(arg(occurrences))
+            .and(next.and_then(arg(regex)))
+            .and(next.and_then(arg(replacement)))
+            .map(|((occ, regex), s)| program::SubNth::new(occ, regex, s, false))
+            .map(From::from),
+    );
+
+    let filter_sub = command(
+        &["filter-sub", "fs"],
+        next.and_then(arg(regex))
+            .and(next.and_then(arg(replacement)))
+            .map(|(regex, s)| program::Sub::new(regex, s, true))
+            .map(From::from),
+    );
+
+    let filter_gsub = command(
+        &["filter-gsub", "fgs"],
+        next.and_then(arg(regex))
+            .and(next.and_then(arg(replacement)))
+            .map(|(regex, s)| program::Gsub::new(regex, s, true))
+            .map(From::from),
+    );
+
+    let filter_sub_nth = command(
+        &["filter-sub-nth", "fsn"],
+        next.and_then(arg(occurrences))
+            .and(next.and_then(arg(regex)))
+            .and(next.and_then(arg(replacement)))
+            .map(|((occ, regex), s)| program::SubNth::new(occ, regex, s, true))
             .map(From::from),
     );
 
@@ -161,43 +244,234 @@ pub fn parse_args(input: Args) -> Result<Program> {
 
     let lines = command(
         &["lines", "line", "l"],
-        next.and_then(arg(all_consuming(separated_list0(char(','), lines_atom))
-            .map(program::Lines::new)
-            .map(From::from))),
+        next.and_then(arg(occurrences.map(program::Lines::new).map(From::from))),
     );
 
     let fields = command(
         &["fields", "F"],
-        next.and_then(arg(all_consuming(separated_list0(char(','), fields_atom))
-            .map(program::Fields::new)
-            .map(From::from))),
+        next.and_then(arg(field_list))
+            .map(move |fields| program::Fields::new(fields, format, format))
+            .map(From::from),
+    );
+
+    let values = command(
+        &["values", "V"],
+        next.and_then(arg(field_list))
+            .map(move |fields| program::Fields::new(fields, format, Format::Plain))
+            .map(From::from),
+    );
+
+    let where_ = command(
+        &["where", "w"],
+        next.and_then(arg(all_consuming(field_id)))
+            .and(next.and_then(arg(regex)))
+            .map(move |(field, regex)| program::Where::new(field, regex, format))
+            .map(From::from),
+    );
+
+    let repeat = command(
+        &["repeat", "rep"],
+        next.and_then(arg(all_consuming(usize)))
+            .and(|i| program(i, options))
+            .and(end)
+            .map(|((max, program), _)| program::Repeat::new(program, max))
+            .map(From::from),
+    );
+
+    let with = command(
+        &["with"],
+        next.and_then(arg(all_consuming(field_id)))
+            .and(|i| program(i, options))
+            .and(end)
+            .map(move |((field, program), _)| program::With::new(field, program, format))
+            .map(From::from),
+    );
+
+    let require = command(
+        &["require"],
+        next.and_then(arg(regex))
+            .map(program::Require::new)
+            .map(From::from),
+    );
+
+    let forbid = command(
+        &["forbid"],
+        next.and_then(arg(regex))
+            .map(program::Forbid::new)
+            .map(From::from),
+    );
+
+    let map = command(
+        &["map"],
+        next.and_then(arg(mapping))
+            .and(next.and_then(arg(all_consuming(selector))))
+            .map(move |(mapping, selector)| {
+                program::Map::new(mapping, selector, program::Unmapped::Keep, format)
+            })
+            .map(From::from),
+    );
+
+    let map_or = command(
+        &["map-or"],
+        next.and_then(arg(mapping))
+            .and(next.and_then(arg(all_consuming(selector))))
+            .and(next)
+            .map(move |((mapping, selector), default)| {
+                let unmapped = program::Unmapped::Default(default.to_owned());
+                program::Map::new(mapping, selector, unmapped, format)
+            })
+            .map(From::from),
+    );
+
+    let filter_map = command(
+        &["filter-map"],
+        next.and_then(arg(mapping))
+            .and(next.and_then(arg(all_consuming(selector))))
+            .map(move |(mapping, selector)| {
+                program::Map::new(mapping, selector, program::Unmapped::Discard, format)
+            })
+            .map(From::from),
+    );
+
+    let filter_in = command(
+        &["filter-in"],
+        next.and_then(arg(set))
+            .and(next.and_then(arg(all_consuming(selector))))
+            .map(move |(set, selector)| program::FilterIn::new(set, selector, false, format))
+            .map(From::from),
+    );
+
+    let filter_not_in = command(
+        &["filter-not-in"],
+        next.and_then(arg(set))
+            .and(next.and_then(arg(all_consuming(selector))))
+            .map(move |(set, selector)| program::FilterIn::new(set, selector, true, format))
+            .map(From::from),
+    );
+
+    let join = |keywords, left| {
+        command(keywords, move |i| {
+            let (i, (((table, (key, other_key)), fields), separator)) = next
+                .and_then(arg(|path| table(path, format, options.header)))
+                .and(next.and_then(arg(all_consuming(|s| join_keys(s, options)))))
+                .and(next.and_then(arg(field_list)))
+                .and(opt(verify(next, not_atom).and_then(arg(escaped))))
+                .parse(i)?;
+            let separator = separator
+                .map(String::from_utf8)
+                .transpose()
+                .map_err(|_| nom::Err::Failure(ParseError::msg("Separator isn't valid UTF-8")))?;
+            program::Join::new(key, table, other_key, fields, left, separator, format)
+                .map(|join| (i, join.into()))
+                .map_err(|err| nom::Err::Error(ParseError::Message(err)))
+        })
+    };
+    let inner_join = join(&["join"], false);
+    let left_join = join(&["left-join"], true);
+
+    let read_file = command(
+        &["read-file", "rf"],
+        next.map(|path| program::ReadFile::new(path.to_owned(), false))
+            .map(From::from),
+    );
+
+    let read_file_replace = command(
+        &["read-file-replace", "rfr"],
+        next.map(|path| program::ReadFile::new(path.to_owned(), true))
+            .map(From::from),
+    );
+
+    let write = command(
+        &["write"],
+        next.map(|path| program::WriteFile::new(path.to_owned()))
+            .map(From::from),
+    );
+
+    let split_to = command(
+        &["split-to"],
+        next.and_then(arg(file_template))
+            .map(program::SplitTo::new)
+            .map(From::from),
+    );
+
+    // the separator is optional, so an argument starting another atom or
+    // closing a group isn't taken as such
+    let collect = command(
+        &["collect"],
+        opt(verify(next, not_atom).and_then(arg(escaped)))
+            .map(|separator| program::Collect::new(separator.unwrap_or_else(|| b"\n".to_vec())))
+            .map(From::from),
     );
 
-    Ok(all_consuming(many0(alt((
+    let exec = command(
+        &["exec"],
+        next.and_then(arg(replacement))
+            .map(|command| program::Exec::new(command, false, options.timeout))
+            .map(From::from),
+    );
+
+    let exec_block = command(
+        &["exec-block"],
+        next.and_then(arg(replacement))
+            .map(|command| program::Exec::new(command, true, options.timeout))
+            .map(From::from),
+    );
+
+    alt((
         enumeration,
         fields,
         filter,
         lines,
         filter_range,
+        filter_sub,
+        filter_gsub,
+        filter_sub_nth,
         gsub,
         match_,
         match_range,
+        repeat,
         sub,
+        sub_nth,
+        values,
+        where_,
+        with,
+        // `alt` takes at most 21 parsers
+        alt((
+            collect,
+            exec,
+            exec_block,
+            filter_in,
+            filter_map,
+            filter_not_in,
+            forbid,
+            inner_join,
+            left_join,
+            map,
+            map_or,
+            read_file,
+            read_file_replace,
+            require,
+            split_to,
+            write,
+        )),
         |i: Args| match i.first() {
-            Some(arg) => Err(nom::Err::Failure(ParseError::msg(format!(
-                "Not a recognized keyword: {}",
-                arg
-            )))),
-            None => Err(nom::Err::Error(ParseError::new())),
+            Some(&"end") | None => Err(nom::Err::Error(ParseError::new())),
+            Some(arg) => Err(nom::Err::Failure(ParseError::UnknownKeyword(
+                arg.to_string(),
+            ))),
         },
-    ))))
+    ))
     .parse(input)
-    .finish()
-    .map(|(_, vec)| vec)
-    .map(Program::new)?)
 }
 
-fn next(input: Args) -> IResult<Args, &str> {
+// Closes a group of atoms
+fn end(input: Args<'_>) -> IResult<Args<'_>, &str> {
+    verify(next, |item: &str| item == "end")
+        .parse(input)
+        .context("Missing 'end'")
+}
+
+fn next(input: Args<'_>) -> IResult<Args<'_>, &str> {
     input
         .split_first()
         .map(|(first, rest)| (rest, *first))
@@ -226,13 +500,115 @@ where
     add_context(|i| format!("Invalid argument: {}", i), parser)
 }
 
-// Consumes the whole input or errors
-fn regex<'s>(input: &'s str) -> IResult<&'s str, Regex> {
-    Regex::new(input).map(|res| ("", res)).map_err(|err| {
-        nom::Err::Error(ParseError::Message(match err {
-            regex::Error::Syntax(m) => anyhow::Error::msg(m),
-            _ => anyhow::Error::msg(format!("Invalid regular expression: {}", input)),
-        }))
+// Consumes the whole input or errors; references to named patterns are
+// expanded first
+fn regex<'s>(input: &'s str, options: &Options) -> IResult<&'s str, Regex> {
+    options
+        .patterns
+        .expand(input)
+        .and_then(|re| Regex::with_engine(&re, options.engine))
+        .map(|res| ("", res))
+        .map_err(|err| nom::Err::Error(ParseError::Message(err)))
+}
+
+#[cfg(test)]
+pub fn parse_replacement(input: &str) -> Result<Replacement> {
+    Ok(replacement(input).finish().map(|(_, res)| res)?)
+}
+
+// Consumes the whole input; `$` and `\` not starting a group reference,
+// case conversion or escape sequence are taken literally.
+fn replacement(s: &str) -> IResult<&str, Replacement> {
+    let group_name = || take_while1(|c: char| c == '_' || c.is_ascii_alphanumeric());
+    let piece = alt((
+        value(Piece::Literal(b"$".to_vec()), tag("$$")),
+        preceded(char('$'), delimited(char('{'), group_name(), char('}'))).map(group_ref),
+        preceded(char('$'), group_name()).map(group_ref),
+        escape.map(Piece::Literal),
+        preceded(char('\\'), one_of("ULulE")).map(|c| {
+            Piece::Case(match c {
+                'U' => Case::Upper,
+                'L' => Case::Lower,
+                'u' => Case::UpperNext,
+                'l' => Case::LowerNext,
+                _ => Case::End,
+            })
+        }),
+        anychar.map(|c| Piece::Literal(c.to_string().into_bytes())),
+    ));
+
+    // merges consecutive literals
+    all_consuming(fold_many0(piece, Vec::new, |mut pieces, piece| {
+        match (pieces.last_mut(), piece) {
+            (Some(Piece::Literal(s)), Piece::Literal(t)) => s.extend(t),
+            (_, piece) => pieces.push(piece),
+        };
+        pieces
+    }))
+    .map(Replacement::new)
+    .parse(s)
+}
+
+// Like `replacement`, but only named groups can be referenced, since file
+// names are built from the named captures of preceding regexes
+fn file_template(s: &str) -> IResult<&str, Replacement> {
+    let (rest, template) = replacement(s)?;
+    if template.has_indexes() {
+        return Err(nom::Err::Failure(ParseError::msg(
+            "File names can only reference named groups, like ${name}",
+        )));
+    }
+    Ok((rest, template))
+}
+
+// Text with the escape sequences of replacements, e.g. a separator
+fn escaped(s: &str) -> IResult<&str, Vec<u8>> {
+    let piece = alt((escape, anychar.map(|c| c.to_string().into_bytes())));
+    all_consuming(fold_many0(piece, Vec::new, |mut text, piece| {
+        text.extend(piece);
+        text
+    }))
+    .parse(s)
+}
+
+// Standard escape sequences: \n, \t, \r, \0, \\, \xHH and \u{...}; `\xHH`
+// stands for the raw byte, so e.g. `\xe9` is Latin-1 rather than UTF-8
+fn escape(s: &str) -> IResult<&str, Vec<u8>> {
+    let utf8 = |c: char| c.to_string().into_bytes();
+    preceded(
+        char('\\'),
+        alt((
+            value(b"\n".to_vec(), char('n')),
+            value(b"\t".to_vec(), char('t')),
+            value(b"\r".to_vec(), char('r')),
+            value(b"\0".to_vec(), char('0')),
+            value(b"\\".to_vec(), char('\\')),
+            preceded(
+                char('x'),
+                take_while_m_n(2, 2, |c: char| c.is_ascii_hexdigit())
+                    .map(|hex| vec![u8::from_str_radix(hex, 16).unwrap()]),
+            ),
+            preceded(
+                char('u'),
+                delimited(
+                    char('{'),
+                    map_opt(
+                        take_while_m_n(1, 6, |c: char| c.is_ascii_hexdigit()),
+                        |hex| char::from_u32(u32::from_str_radix(hex, 16).unwrap()),
+                    ),
+                    char('}'),
+                ),
+            )
+            .map(utf8),
+        )),
+    )
+    .parse(s)
+}
+
+fn group_ref(name: &str) -> Piece {
+    Piece::Group(match name.parse() {
+        Ok(i) => GroupRef::Index(i),
+        Err(_) => GroupRef::Name(name.to_owned()),
     })
 }
 
@@ -240,22 +616,136 @@ fn usize(s: &str) -> IResult<&str, usize> {
     map_res(digit1, |s: &str| s.parse::<usize>()).parse(s)
 }
 
-fn field_id(s: &str) -> IResult<&str, program::FieldId> {
+// JSON values are only selected by key or path, which in turn can't be used
+// with other formats
+fn field_id<'s>(s: &'s str, options: &Options) -> IResult<&'s str, program::FieldId> {
+    use program::FieldId;
+    let (rest, id) = alt((
+        usize.map(FieldId::Int),
+        delimited(char('('), preceded(char('-'), usize), char(')')).map(FieldId::FromLast),
+        json_path.map(FieldId::Path),
+        |s| named_field(s, options).map(|(s, name)| (s, FieldId::Name(name.to_owned()))),
+    ))
+    .parse(s)?;
+    match (options.format, &id) {
+        (Format::Json, FieldId::Int(_) | FieldId::FromLast(_)) => Err(nom::Err::Failure(
+            ParseError::msg("Values in JSON can only be selected by key or path"),
+        )),
+        (Format::Json, _) => Ok((rest, id)),
+        (_, FieldId::Path(_)) => Err(nom::Err::Failure(ParseError::msg(
+            "Paths can only be used with JSON input",
+        ))),
+        _ => Ok((rest, id)),
+    }
+}
+
+// Either a field or a named capture, written as `$name` or `${name}`
+fn selector<'s>(s: &'s str, options: &Options) -> IResult<&'s str, program::Selector> {
+    let name = || take_while1(|c: char| c == '_' || c.is_ascii_alphanumeric());
     alt((
-        usize.map(program::FieldId::Int),
-        delimited(char('('), preceded(char('-'), usize), char(')')).map(program::FieldId::FromLast),
+        preceded(
+            char('$'),
+            alt((delimited(char('{'), name(), char('}')), name())),
+        )
+        .map(|name: &str| program::Selector::Capture(name.to_owned())),
+        (|s| field_id(s, options)).map(program::Selector::Field),
     ))
     .parse(s)
 }
 
-fn fields_atom(s: &str) -> IResult<&str, program::FieldsAtom> {
+// Loads the mapping file at the given path
+fn mapping(path: &str) -> IResult<&str, HashMap<String, String>> {
+    table::load_map(path)
+        .map(|res| ("", res))
+        .map_err(|err| nom::Err::Error(ParseError::Message(err)))
+}
+
+// `<field>=<field of the other table>` or a single field used for both
+fn join_keys<'s>(
+    s: &'s str,
+    options: &Options,
+) -> IResult<&'s str, (program::FieldId, program::FieldId)> {
+    let side = || take_while1(|c| c != '=').and_then(all_consuming(|s| field_id(s, options)));
+    alt((
+        separated_pair(side(), char('='), side()),
+        side().map(|id| (id.clone(), id)),
+    ))
+    .parse(s)
+}
+
+// Loads the records of the file at the given path
+fn table(path: &str, format: Format, header: bool) -> IResult<&str, table::Table> {
+    table::load_table(path, format, header)
+        .map(|res| ("", res))
+        .map_err(|err| nom::Err::Error(ParseError::Message(err)))
+}
+
+// Loads the file with one entry per line at the given path
+fn set(path: &str) -> IResult<&str, HashSet<String>> {
+    table::load_set(path)
+        .map(|res| ("", res))
+        .map_err(|err| nom::Err::Error(ParseError::Message(err)))
+}
+
+// Names of fields, e.g. in the header of a CSV file. They must not start
+// with a digit and can't contain the characters used in the field syntax
+// unless they are quoted, as in `"first-name"`.
+fn field_name(s: &str) -> IResult<&str, &str> {
     alt((
-        separated_pair(opt(field_id), char('-'), opt(field_id))
+        delimited(char('"'), take_while1(|c| c != '"'), char('"')),
+        verify(take_while1(|c| !",-()".contains(c)), |name: &str| {
+            !name.starts_with(|c: char| c.is_ascii_digit())
+        }),
+    ))
+    .parse(s)
+}
+
+// A field name, which is looked up in the header or, for JSON and logfmt,
+// in the records themselves
+fn named_field<'s>(s: &'s str, options: &Options) -> IResult<&'s str, &'s str> {
+    let (rest, name) = field_name(s)?;
+    match options.format {
+        Format::Plain | Format::Delimited(_) if !options.header => Err(nom::Err::Failure(
+            ParseError::msg(format!("Field name '{}' used without a header", name)),
+        )),
+        _ => Ok((rest, name)),
+    }
+}
+
+// E.g. `.`, `.request.method`, `.tags[0]` or `.headers["user-agent"]`
+fn json_path(s: &str) -> IResult<&str, Path> {
+    let key =
+        || take_while1(|c| !".[,-()".contains(c)).map(|key: &str| Segment::Key(key.to_owned()));
+    let quoted_key = delimited(tag("[\""), take_while(|c| c != '"'), tag("\"]"))
+        .map(|key: &str| Segment::Key(key.to_owned()));
+    let index = delimited(char('['), usize, char(']')).map(Segment::Index);
+
+    preceded(char('.'), opt(key()))
+        .and(many0(alt((preceded(char('.'), key()), quoted_key, index))))
+        .map(|(first, rest)| Path::new(first.into_iter().chain(rest).collect()))
+        .parse(s)
+}
+
+fn fields_atom<'s>(s: &'s str, options: &Options) -> IResult<&'s str, program::FieldsAtom> {
+    let field_id = || move |s| field_id(s, options);
+    let (rest, atom) = alt((
+        separated_pair(opt(field_id()), char('-'), opt(field_id()))
             .map(|(opt1, opt2)| program::OpenRange::new(opt1, opt2))
             .map(program::FieldsAtom::Range),
-        field_id.map(program::FieldsAtom::Single),
+        field_id().map(program::FieldsAtom::Single),
     ))
-    .parse(s)
+    .parse(s)?;
+    match atom {
+        program::FieldsAtom::Range(_) if options.format == Format::Json => Err(nom::Err::Failure(
+            ParseError::msg("Ranges of fields can't be used with JSON"),
+        )),
+        atom => Ok((rest, atom)),
+    }
+}
+
+// A comma-separated list of `lines_atom`s, consuming the whole input
+fn occurrences(s: &str) -> IResult<&str, Vec<program::LinesAtom>> {
+    all_consuming(separated_list0(char(','), lines_atom)).parse(s)
 }
 
 fn lines_atom(s: &str) -> IResult<&str, program::LinesAtom> {
@@ -267,3 +757,57 @@ fn lines_atom(s: &str) -> IResult<&str, program::LinesAtom> {
     ))
     .parse(s)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(format: Format, header: bool) -> Options {
+        Options {
+            format,
+            patterns: Patterns::new(),
+            engine: Default::default(),
+            header,
+            timeout: None,
+        }
+    }
+
+    #[test]
+    fn test_field_names() {
+        let parse = |args: &[&str], options| parse_args(args, &options).map(|_| ());
+
+        assert!(parse(&["fields", "name"], options(Format::Plain, false)).is_err());
+        assert!(parse(
+            &["where", "name", "x"],
+            options(Format::Delimited(','), false)
+        )
+        .is_err());
+        assert!(parse(&["fields", "1-2"], options(Format::Delimited(','), false)).is_ok());
+        assert!(parse(&["fields", "name"], options(Format::Logfmt, false)).is_ok());
+        assert!(parse(&["fields", "name"], options(Format::Plain, true)).is_ok());
+
+        let json = || options(Format::Json, false);
+        assert!(parse(&["fields", ".a,.b[0],c"], json()).is_ok());
+        assert!(parse(&["fields", ".a-.b"], json()).is_err());
+        assert!(parse(&["where", "2", "x"], json()).is_err());
+        assert!(parse(&["fields", ".a"], options(Format::Delimited(','), true)).is_err());
+
+        assert_eq!(
+            field_list_of(r#""first-name",a-"b,c""#),
+            vec![
+                program::FieldsAtom::Single(program::FieldId::Name("first-name".to_owned())),
+                program::FieldsAtom::Range(program::OpenRange::new(
+                    Some(program::FieldId::Name("a".to_owned())),
+                    Some(program::FieldId::Name("b,c".to_owned()))
+                )),
+            ]
+        );
+    }
+
+    fn field_list_of(s: &str) -> Vec<program::FieldsAtom> {
+        let options = options(Format::Plain, true);
+        let (_, fields) =
+            all_consuming(separated_list0(char(','), |s| fields_atom(s, &options)))(s).unwrap();
+        fields
+    }
+}
@@ -1,35 +1,216 @@
+use crate::engine::{Capture, Captures, Regex};
+use crate::exec;
+use crate::json::{self, Path, Segment};
+use crate::logfmt;
+use crate::record::{self, Format};
+use crate::replacement::Replacement;
+use crate::table::Table;
+use crate::Result;
+use anyhow::{bail, Context};
 use enum_dispatch::enum_dispatch;
-use regex::Regex;
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::mem;
+use std::ops::Range;
+use std::path::{self, PathBuf};
+use std::time::Duration;
 
 #[derive(Debug, PartialEq)]
 enum Output {
-    Resume(String),
-    ResetAndResume(String),
-    Return(Option<String>),
-}
-
-impl Output {
-    fn as_opt(self) -> Option<String> {
-        match self {
-            Output::Resume(s) => Some(s),
-            Output::ResetAndResume(s) => Some(s),
-            Output::Return(opt) => opt,
-        }
-    }
+    Resume(Vec<u8>),
+    ResetAndResume(Vec<u8>),
+    EndAndResume(Vec<u8>), // the current block ends with this input
+    // the input is replaced by several (at least one) records, each passed
+    // on to the following atoms
+    Several(Vec<Vec<u8>>),
+    Return(Option<Vec<u8>>),
 }
 
 #[enum_dispatch]
 #[derive(Debug)]
 pub enum Atom {
+    Collect,
     Enumeration,
+    Exec,
     Filter,
+    FilterIn,
+    Forbid,
+    Join,
     Lines,
+    Map,
+    ReadFile,
     FilterRange,
     Fields,
     Gsub,
     Match,
     MatchRange,
+    Repeat,
+    Require,
+    SplitTo,
     Sub,
+    SubNth,
+    Where,
+    With,
+    WriteFile,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Highlight {
+    Match,     // matched by `filter` or `match`
+    Insertion, // inserted by a substitution
+}
+
+// What atoms record about the matches of their regexes. Both are costly to
+// find, so they are only tracked if used, e.g. by the output or a later atom.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Track {
+    pub captures: bool,
+    pub positions: bool, // of matches, i.e. the column and spans
+}
+
+impl Default for Track {
+    fn default() -> Track {
+        Track {
+            captures: true,
+            positions: true,
+        }
+    }
+}
+
+// Information about an input gathered while it passes through the atoms
+#[derive(Debug, Default, PartialEq)]
+pub struct Meta {
+    // named groups of the last regex that matched, in the order of the groups
+    pub captures: Vec<Capture>,
+    // 1-based byte column of the last match of a `filter` or `match` atom
+    pub column: Option<usize>,
+    // byte ranges of the current text worth highlighting
+    pub spans: Vec<(Range<usize>, Highlight)>,
+    // 1-based number of the input line
+    pub line: usize,
+    // lines to print after the input, even if it is discarded
+    pub appended: Vec<Vec<u8>>,
+    // records emitted by atoms at the end of a block, to be printed before
+    // the input; `None` if discarded by a later atom
+    pub emitted: Vec<(Option<Vec<u8>>, Meta)>,
+    // records emitted by a group of atoms like `repeat`, still to be passed
+    // to the atoms after the group
+    pub pending: Vec<(Vec<u8>, Meta)>,
+    pub track: Track,
+    // failed checks, which are all reported before saw fails
+    pub violations: Vec<Violation>,
+    // whether the whole input ended rather than just a block
+    pub ended: bool,
+    // how line breaks in the text and the end of the record are written,
+    // e.g. by `write`; empty if unknown
+    pub line_break: &'static str,
+    pub terminator: &'static str,
+}
+
+impl Meta {
+    // Returns the range of the match, as far as it is tracked
+    fn find(&mut self, regex: &Regex, text: &[u8]) -> Result<Option<Range<usize>>> {
+        if !self.track.captures {
+            if self.track.positions {
+                return regex.find(text);
+            }
+            // any range will do
+            return Ok(regex.is_match(text)?.then_some(0..0));
+        }
+        let caps = regex.captures(text)?;
+        if let Some(caps) = &caps {
+            self.captures = regex.named_captures(caps);
+        }
+        // the whole match is always present
+        Ok(caps.map(|caps| caps.get(0).unwrap().range()))
+    }
+
+    // Like `Regex::is_match`, but records the captures of a match
+    fn is_match(&mut self, regex: &Regex, text: &[u8]) -> Result<bool> {
+        Ok(self.find(regex, text)?.is_some())
+    }
+
+    // Like `is_match` for a part of the input, e.g. a single field, where the
+    // positions of the captures in the input are unknown
+    fn is_match_in(&mut self, regex: &Regex, part: &[u8]) -> Result<bool> {
+        let res = self.is_match(regex, part)?;
+        if res && regex.has_names() {
+            self.captures.iter_mut().for_each(|c| c.range = None);
+        }
+        Ok(res)
+    }
+
+    pub fn capture(&self, name: &str) -> Option<&Capture> {
+        self.captures.iter().find(|c| c.name == name)
+    }
+
+    // Like `is_match`, but also records the column and span of the match
+    fn locate(&mut self, regex: &Regex, text: &[u8]) -> Result<bool> {
+        Ok(match self.find(regex, text)? {
+            Some(_) if !self.track.positions => true,
+            Some(span) => {
+                self.column = Some(span.start + 1);
+                self.spans.push((span, Highlight::Match));
+                true
+            }
+            None => false,
+        })
+    }
+
+    // Moves the spans and captures according to the replacement of each
+    // `old` range of the text by the `new` one; both are given in ascending
+    // order. Positions overlapping a replaced range are dropped.
+    fn replaced(&mut self, edits: &[(Range<usize>, Range<usize>)]) {
+        self.spans.retain_mut(|(span, _)| match moved(span, edits) {
+            Some(new) => {
+                *span = new;
+                true
+            }
+            None => false,
+        });
+        for capture in self.captures.iter_mut() {
+            capture.range = capture.range.as_ref().and_then(|range| moved(range, edits));
+        }
+    }
+
+    // Drops all positions, e.g. after the input was rewritten as a whole
+    fn forget_positions(&mut self) {
+        self.spans.clear();
+        self.captures.iter_mut().for_each(|c| c.range = None);
+    }
+
+    // Starting point for the metadata of a record emitted at the end of a
+    // block, which keeps the captures but none of the positions
+    fn for_emitted(&self) -> Meta {
+        let mut meta = Meta {
+            captures: self.captures.clone(),
+            line: self.line,
+            track: self.track,
+            ended: self.ended,
+            line_break: self.line_break,
+            terminator: self.terminator,
+            ..Meta::default()
+        };
+        meta.forget_positions();
+        meta
+    }
+}
+
+fn moved(range: &Range<usize>, edits: &[(Range<usize>, Range<usize>)]) -> Option<Range<usize>> {
+    let mut shift = (0, 0);
+    for (old, new) in edits {
+        if old.end <= range.start {
+            shift = (old.end, new.end);
+        } else if old.start >= range.end {
+            break;
+        } else {
+            return None;
+        }
+    }
+    Some(range.start - shift.0 + shift.1..range.end - shift.0 + shift.1)
 }
 
 #[derive(Debug)]
@@ -40,33 +221,194 @@ impl Program {
         Program(inner)
     }
 
-    pub fn run(&mut self, arg: String) -> Option<String> {
-        let mut reset = false;
+    pub fn reset(&mut self) {
+        self.0.iter_mut().for_each(|atom| atom.reset());
+    }
+
+    // Called at the end of the input; records emitted by the atoms are added
+    // to `meta.emitted`
+    pub fn finish(&mut self, meta: &mut Meta) -> Result<()> {
+        self.finish_from(0, meta)
+    }
+
+    // Whether captures have to be tracked for any of the atoms
+    pub fn uses_captures(&self) -> bool {
+        self.0.iter().any(|atom| atom.uses_captures())
+    }
 
+    // Atoms can't discard the header, but may modify it
+    pub fn header(&mut self, header: Vec<u8>) -> Result<Vec<u8>> {
         self.0
             .iter_mut()
-            .fold(Output::Resume(arg), |out, atom| {
-                if reset {
-                    atom.reset();
-                };
-                match out {
-                    Output::Resume(s) => atom.run(s),
-                    Output::ResetAndResume(s) => {
-                        reset = true;
-                        atom.reset();
-                        atom.run(s)
-                    }
-                    res @ Output::Return(_) => res,
+            .try_fold(header, |header, atom| atom.header(header))
+    }
+
+    pub fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Option<Vec<u8>>> {
+        self.run_from(0, arg, meta)
+    }
+
+    fn run_from(&mut self, start: usize, arg: Vec<u8>, meta: &mut Meta) -> Result<Option<Vec<u8>>> {
+        let mut out = Output::Resume(arg);
+        for i in start..self.0.len() {
+            out = match out {
+                Output::Resume(s) => self.0[i].run(s, meta)?,
+                Output::Several(args) => {
+                    let s = self.emit_from(i, args, meta)?;
+                    self.0[i].run(s, meta)?
                 }
-            })
-            .as_opt()
+                Output::ResetAndResume(s) => {
+                    // a new block starts for all remaining atoms
+                    self.finish_from(i, meta)?;
+                    self.0[i..].iter_mut().for_each(|atom| atom.reset());
+                    self.0[i].run(s, meta)?
+                }
+                Output::EndAndResume(s) => {
+                    // the block ends once the input passed the remaining atoms
+                    let res = self.run_from(i, s, meta)?;
+                    self.finish_from(i, meta)?;
+                    return Ok(res);
+                }
+                Output::Return(res) => return Ok(res),
+            };
+            self.pass_pending(i, meta)?;
+        }
+        Ok(match out {
+            Output::Resume(s) | Output::ResetAndResume(s) | Output::EndAndResume(s) => Some(s),
+            Output::Several(args) => Some(self.emit_from(self.0.len(), args, meta)?),
+            Output::Return(res) => res,
+        })
+    }
+
+    // Emits all but the last of several records replacing an input after
+    // passing them through the atoms from `start` on; the last one is
+    // returned to take the place of the input
+    fn emit_from(
+        &mut self,
+        start: usize,
+        mut args: Vec<Vec<u8>>,
+        meta: &mut Meta,
+    ) -> Result<Vec<u8>> {
+        let last = args.pop().unwrap();
+        for arg in args {
+            let mut emitted = meta.for_emitted();
+            let res = self.run_from(start, arg, &mut emitted)?;
+            meta.violations.append(&mut emitted.violations);
+            meta.emitted.push((res, emitted));
+        }
+        Ok(last)
+    }
+
+    // Ends the current block of the atoms from `start` on. A record emitted by
+    // one of them is passed on to the atoms after it, which are finished only
+    // afterwards.
+    fn finish_from(&mut self, start: usize, meta: &mut Meta) -> Result<()> {
+        for i in start..self.0.len() {
+            let mut emitted = meta.for_emitted();
+            let arg = self.0[i].finish(&mut emitted)?;
+            self.pass_pending(i, &mut emitted)?;
+            meta.violations.append(&mut emitted.violations);
+            match arg {
+                Some(arg) => {
+                    let res = self.run_from(i + 1, arg, &mut emitted)?;
+                    meta.violations.append(&mut emitted.violations);
+                    meta.emitted.push((res, emitted));
+                }
+                None => meta.emitted.append(&mut emitted.emitted),
+            }
+        }
+        Ok(())
+    }
+
+    // Passes the records emitted by the group of atoms at `i` on to the
+    // atoms after it
+    fn pass_pending(&mut self, i: usize, meta: &mut Meta) -> Result<()> {
+        for (arg, mut emitted) in mem::take(&mut meta.pending) {
+            let res = self.run_from(i + 1, arg, &mut emitted)?;
+            meta.violations.append(&mut emitted.violations);
+            meta.emitted.push((res, emitted));
+        }
+        Ok(())
     }
 }
 
 #[enum_dispatch(Atom)]
 trait ProgramAtom {
-    fn run(&mut self, arg: String) -> Output;
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output>;
     fn reset(&mut self) {}
+
+    // Called with the header line, if any, before the first input
+    fn header(&mut self, header: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(header)
+    }
+
+    // Called when the current block ends, i.e. after the last input of a
+    // range, before the atom is reset by one and at the end of the input. The
+    // atom may emit a final record, described by `meta`.
+    fn finish(&mut self, _meta: &mut Meta) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    // Whether the atom reads the captures of preceding regexes
+    fn uses_captures(&self) -> bool {
+        false
+    }
+}
+
+// Failed check of a `require` or `forbid` atom
+#[derive(Debug, PartialEq)]
+pub struct Violation {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for Violation {}
+
+// Failure of an atom for the input at a line, e.g. of a command it ran
+#[derive(Debug)]
+pub struct LineError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for LineError {}
+
+fn at_line(line: usize) -> impl FnOnce(anyhow::Error) -> anyhow::Error {
+    move |err| {
+        LineError {
+            line,
+            message: format!("{:#}", err),
+        }
+        .into()
+    }
+}
+
+// A record isn't valid UTF-8, so fields and values can't be taken from it
+#[derive(Debug)]
+pub struct InvalidText;
+
+impl fmt::Display for InvalidText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Fields and values can't be taken from invalid UTF-8")
+    }
+}
+
+impl std::error::Error for InvalidText {}
+
+// Text of a record for atoms that split it into fields or look up values
+fn text(record: &[u8]) -> Result<&str> {
+    Ok(std::str::from_utf8(record).map_err(|_| InvalidText)?)
 }
 
 #[derive(Debug)]
@@ -80,78 +422,509 @@ impl Match {
 }
 
 impl ProgramAtom for Match {
-    fn run(&mut self, arg: String) -> Output {
-        if self.regex.is_match(&arg) {
-            Output::Resume(arg)
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        if meta.locate(&self.regex, &arg)? {
+            Ok(Output::Resume(arg))
         } else {
-            Output::Return(Some(arg))
+            Ok(Output::Return(Some(arg)))
         }
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum FieldId {
     Int(usize),
     FromLast(usize), // FromLast(-1) represents the last field
+    Name(String),    // resolved to an `Int` using the header
+    Path(Path),      // only valid for JSON
 }
 impl FieldId {
-    fn to_usize(self, last: usize) -> usize {
+    pub fn to_usize(&self, last: usize) -> Result<usize> {
         match self {
-            FieldId::Int(i) => i,
-            FieldId::FromLast(i) => {
-                if last + 1 >= i {
-                    last + 1 - i
-                } else {
-                    0
-                }
-            }
+            FieldId::Int(i) => Ok(*i),
+            FieldId::FromLast(i) => Ok((last + 1).saturating_sub(*i)),
+            FieldId::Name(name) => bail!("Field name '{}' used without a header", name),
+            FieldId::Path(_) => bail!("Paths can only be used with JSON input"),
+        }
+    }
+
+    pub fn to_path(&self) -> Result<Path> {
+        match self {
+            FieldId::Name(name) => Ok(Path::new(vec![Segment::Key(name.clone())])),
+            FieldId::Path(path) => Ok(path.clone()),
+            _ => bail!("Values in JSON can only be selected by key or path"),
         }
     }
+
+    fn resolve(&mut self, header: &[String]) -> Result<()> {
+        if let FieldId::Name(name) = self {
+            match header.iter().position(|field| field == name) {
+                Some(n) => *self = FieldId::Int(n + 1),
+                None => bail!("Unknown field: {}", name),
+            }
+        };
+        Ok(())
+    }
+
+    pub fn get<'f>(&self, fields: &'f [String]) -> Result<Option<&'f String>> {
+        let n = self.to_usize(fields.len())?;
+        Ok(n.checked_sub(1).and_then(|n| fields.get(n)))
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum FieldsAtom {
     Single(FieldId),
     Range(OpenRange<FieldId>),
 }
 impl FieldsAtom {
-    fn contains(&self, n: usize, last: usize) -> bool {
+    fn contains(&self, n: usize, last: usize) -> Result<bool> {
+        Ok(match self {
+            FieldsAtom::Single(id) => n == id.to_usize(last)?,
+            FieldsAtom::Range(range) => range.try_map(|id| id.to_usize(last))?.contains(n),
+        })
+    }
+
+    fn resolve(&mut self, header: &[String]) -> Result<()> {
         match self {
-            FieldsAtom::Single(id) => n == id.to_usize(last),
-            FieldsAtom::Range(range) => range.map(|id| id.to_usize(last)).contains(n),
+            FieldsAtom::Single(id) => id.resolve(header),
+            FieldsAtom::Range(range) => {
+                for id in range
+                    .lower_bound
+                    .iter_mut()
+                    .chain(range.upper_bound.iter_mut())
+                {
+                    id.resolve(header)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Like `resolve`, but returns `None` if a name is unknown
+    fn resolved(&self, names: &[String]) -> Option<FieldsAtom> {
+        let mut res = self.clone();
+        res.resolve(names).ok().map(|_| res)
+    }
+}
+
+fn any_contains(fields: &[FieldsAtom], n: usize, last: usize) -> Result<bool> {
+    for atom in fields {
+        if atom.contains(n, last)? {
+            return Ok(true);
         }
     }
+    Ok(false)
 }
 
+// Projects the input onto the selected fields; the result is formatted
+// according to `output`, which might differ from the input format.
 #[derive(Debug, PartialEq)]
-pub struct Fields(Vec<FieldsAtom>);
+pub struct Fields {
+    fields: Vec<FieldsAtom>,
+    format: Format,
+    output: Format,
+}
 impl Fields {
-    pub fn contains(&self, n: usize, last: usize) -> bool {
-        self.0.iter().any(|fields| fields.contains(n, last))
+    pub fn new(fields: Vec<FieldsAtom>, format: Format, output: Format) -> Fields {
+        Fields {
+            fields,
+            format,
+            output,
+        }
+    }
+
+    fn project(&self, arg: &str) -> Result<String> {
+        match self.format {
+            Format::Json => return self.project_json(arg),
+            Format::Logfmt => return self.project_logfmt(arg),
+            _ => (),
+        };
+        let fields = self.format.split(arg)?;
+        let len = fields.len();
+        let mut res = Vec::new();
+        for (n, field) in fields.iter().enumerate() {
+            if any_contains(&self.fields, n + 1, len)? {
+                res.push(field);
+            }
+        }
+        self.output.join(&res)
+    }
+
+    // Missing values are skipped
+    fn project_json(&self, arg: &str) -> Result<String> {
+        let value = record::parse_json(arg)?;
+        let mut paths = Vec::new();
+        for fields in &self.fields {
+            match fields {
+                FieldsAtom::Single(id) => paths.push(id.to_path()?),
+                FieldsAtom::Range(_) => bail!("Ranges of fields can't be used with JSON"),
+            }
+        }
+        let found = paths
+            .iter()
+            .filter_map(|path| path.get(&value).map(|value| (path, value)));
+
+        if self.output == Format::Json {
+            let mut res = serde_json::Value::Object(Default::default());
+            for (path, value) in found {
+                path.insert(&mut res, value.clone());
+            }
+            Ok(res.to_string())
+        } else {
+            let values: Vec<_> = found.map(|(_, value)| json::to_text(value)).collect();
+            self.output.join(&values)
+        }
     }
 
-    pub fn new(source: Vec<FieldsAtom>) -> Fields {
-        Fields(source)
+    // Keys are looked up in each record, missing ones are skipped
+    fn project_logfmt(&self, arg: &str) -> Result<String> {
+        let pairs = logfmt::parse(arg);
+        let keys: Vec<_> = pairs.iter().map(|(key, _)| key.clone()).collect();
+        let fields: Vec<_> = self
+            .fields
+            .iter()
+            .filter_map(|atom| atom.resolved(&keys))
+            .collect();
+
+        let mut res = Vec::new();
+        for (n, pair) in pairs.iter().enumerate() {
+            if any_contains(&fields, n + 1, pairs.len())? {
+                res.push(pair);
+            }
+        }
+
+        if self.output == Format::Logfmt {
+            Ok(logfmt::join(&res))
+        } else {
+            // bare keys have no value to print
+            let values: Vec<_> = res
+                .into_iter()
+                .filter_map(|(_, value)| value.clone())
+                .collect();
+            self.output.join(&values)
+        }
     }
 }
 
 impl ProgramAtom for Fields {
-    fn run(&mut self, arg: String) -> Output {
-        let fields: Vec<_> = arg.split(' ').filter(|s| s != &"").collect();
-        let len = fields.len();
-        Output::Resume(
-            fields
-                .into_iter()
-                .enumerate()
-                .filter(|(n, _)| self.contains(n + 1, len))
-                .map(|(_, f)| f)
-                .intersperse(" ")
-                .collect(),
-        )
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        meta.forget_positions();
+        let res = self.project(text(&arg)?)?;
+        Ok(Output::Resume(res.into_bytes()))
+    }
+
+    fn header(&mut self, header: Vec<u8>) -> Result<Vec<u8>> {
+        let header = text(&header)?;
+        let names = self.format.split(header)?;
+        for fields in self.fields.iter_mut() {
+            fields.resolve(&names)?;
+        }
+        Ok(self.project(header)?.into_bytes())
     }
 }
 
-#[derive(Debug, PartialEq)]
+// Filters for inputs whose given field matches a regex
+#[derive(Debug)]
+pub struct Where {
+    field: FieldId,
+    regex: Regex,
+    format: Format,
+}
+impl Where {
+    pub fn new(field: FieldId, regex: Regex, format: Format) -> Where {
+        Where {
+            field,
+            regex,
+            format,
+        }
+    }
+}
+
+impl ProgramAtom for Where {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        match self.format.get(text(&arg)?, &self.field)? {
+            Some(field) if meta.is_match_in(&self.regex, field.as_bytes())? => {
+                Ok(Output::Resume(arg))
+            }
+            _ => Ok(Output::Return(None)),
+        }
+    }
+
+    fn header(&mut self, header: Vec<u8>) -> Result<Vec<u8>> {
+        self.field.resolve(&self.format.split(text(&header)?)?)?;
+        Ok(header)
+    }
+}
+
+// Selects part of an input, either a field or the text of a named capture
+#[derive(Debug, PartialEq, Clone)]
+pub enum Selector {
+    Field(FieldId),
+    Capture(String),
+}
+
+impl Selector {
+    fn get(&self, record: &[u8], meta: &Meta, format: Format) -> Result<Option<String>> {
+        match self {
+            Selector::Field(field) => format.get(text(record)?, field),
+            Selector::Capture(name) => meta
+                .capture(name)
+                .map(|c| text(&c.value).map(str::to_owned))
+                .transpose(),
+        }
+    }
+
+    fn resolve(&mut self, header: &[u8], format: Format) -> Result<()> {
+        match self {
+            Selector::Field(field) => field.resolve(&format.split(text(header)?)?),
+            Selector::Capture(_) => Ok(()),
+        }
+    }
+
+    fn is_capture(&self) -> bool {
+        matches!(self, Selector::Capture(_))
+    }
+}
+
+// What `Map` does with inputs whose key is not in the mapping
+#[derive(Debug, PartialEq, Clone)]
+pub enum Unmapped {
+    Keep,
+    Default(String),
+    Discard,
+}
+
+// Replaces the selected part of the input by the value it is mapped to
+#[derive(Debug)]
+pub struct Map {
+    mapping: HashMap<String, String>,
+    selector: Selector,
+    unmapped: Unmapped,
+    format: Format,
+}
+impl Map {
+    pub fn new(
+        mapping: HashMap<String, String>,
+        selector: Selector,
+        unmapped: Unmapped,
+        format: Format,
+    ) -> Map {
+        Map {
+            mapping,
+            selector,
+            unmapped,
+            format,
+        }
+    }
+
+    // `None` if the input is to be discarded
+    fn lookup(&self, key: &str) -> Option<String> {
+        match (self.mapping.get(key), &self.unmapped) {
+            (Some(value), _) => Some(value.clone()),
+            (None, Unmapped::Keep) => Some(key.to_owned()),
+            (None, Unmapped::Default(value)) => Some(value.clone()),
+            (None, Unmapped::Discard) => None,
+        }
+    }
+}
+
+impl ProgramAtom for Map {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        let key = match self.selector.get(&arg, meta, self.format)? {
+            Some(key) => key,
+            None => return Ok(Output::Resume(arg)),
+        };
+        let value = match self.lookup(&key) {
+            Some(value) if value == key => return Ok(Output::Resume(arg)),
+            Some(value) => value,
+            None => return Ok(Output::Return(None)),
+        };
+        match &self.selector {
+            Selector::Field(field) => {
+                meta.forget_positions();
+                let res = self.format.set(text(&arg)?, field, value)?;
+                Ok(Output::Resume(res.into_bytes()))
+            }
+            Selector::Capture(name) => {
+                // e.g. the capture of the start of a range, which can't be
+                // replaced in the inputs that follow it
+                let range = match meta.capture(name).and_then(|c| c.range.clone()) {
+                    Some(range) => range,
+                    None => return Ok(Output::Resume(arg)),
+                };
+
+                let new = range.start..range.start + value.len();
+                meta.replaced(&[(range.clone(), new.clone())]);
+                meta.spans.push((new.clone(), Highlight::Insertion));
+                // the replaced capture now refers to its new value
+                if let Some(capture) = meta.captures.iter_mut().find(|c| c.name == *name) {
+                    capture.value = value.clone().into_bytes();
+                    capture.range = Some(new);
+                }
+                Ok(Output::Resume(
+                    [&arg[..range.start], value.as_bytes(), &arg[range.end..]].concat(),
+                ))
+            }
+        }
+    }
+
+    fn header(&mut self, header: Vec<u8>) -> Result<Vec<u8>> {
+        self.selector.resolve(&header, self.format)?;
+        Ok(header)
+    }
+
+    fn uses_captures(&self) -> bool {
+        self.selector.is_capture()
+    }
+}
+
+// Appends the selected fields of the rows of another table whose key equals
+// the one of the input, like `join(1)` but without the need for sorting. If
+// several rows match, the input is passed on once for each of them. Inputs
+// without match are discarded unless `left` is set.
+#[derive(Debug)]
+pub struct Join {
+    key: FieldId,
+    index: HashMap<String, Vec<Vec<String>>>,
+    header: Option<Vec<String>>, // selected names of the other table
+    left: bool,
+    separator: Option<String>, // instead of the one of the format
+    format: Format,
+}
+impl Join {
+    // `other_key` and `fields` refer to fields of `table`
+    pub fn new(
+        key: FieldId,
+        table: Table,
+        mut other_key: FieldId,
+        mut fields: Vec<FieldsAtom>,
+        left: bool,
+        separator: Option<String>,
+        format: Format,
+    ) -> Result<Join> {
+        if let Some(names) = &table.header {
+            other_key.resolve(names)?;
+            for fields in fields.iter_mut() {
+                fields.resolve(names)?;
+            }
+        }
+        let select = |row: &[String]| -> Result<Vec<String>> {
+            let mut res = Vec::new();
+            for (n, field) in row.iter().enumerate() {
+                if any_contains(&fields, n + 1, row.len())? {
+                    res.push(field.clone());
+                }
+            }
+            Ok(res)
+        };
+
+        let mut index: HashMap<_, Vec<_>> = HashMap::new();
+        for row in &table.rows {
+            if let Some(key) = other_key.get(row)? {
+                index.entry(key.clone()).or_default().push(select(row)?);
+            }
+        }
+        Ok(Join {
+            key,
+            index,
+            header: table.header.as_deref().map(select).transpose()?,
+            left,
+            separator,
+            format,
+        })
+    }
+
+    fn append(&self, record: &str, fields: &[String]) -> Result<String> {
+        if fields.is_empty() {
+            return Ok(record.to_owned());
+        }
+        match &self.separator {
+            Some(sep) => Ok(format!("{}{}{}", record, sep, fields.join(sep))),
+            None => Ok(format!(
+                "{}{}{}",
+                record,
+                self.format.separator()?,
+                self.format.join(fields)?
+            )),
+        }
+    }
+}
+
+impl ProgramAtom for Join {
+    fn run(&mut self, arg: Vec<u8>, _meta: &mut Meta) -> Result<Output> {
+        let record = text(&arg)?;
+        let rows = match self.format.get(record, &self.key)? {
+            Some(key) => self.index.get(&key),
+            None => None,
+        };
+        match rows {
+            Some(rows) => {
+                let res = rows
+                    .iter()
+                    .map(|row| Ok(self.append(record, row)?.into_bytes()))
+                    .collect::<Result<_>>()?;
+                Ok(Output::Several(res))
+            }
+            None if self.left => Ok(Output::Resume(arg)),
+            None => Ok(Output::Return(None)),
+        }
+    }
+
+    fn header(&mut self, header: Vec<u8>) -> Result<Vec<u8>> {
+        let record = text(&header)?;
+        self.key.resolve(&self.format.split(record)?)?;
+        Ok(match &self.header {
+            Some(names) => self.append(record, names)?.into_bytes(),
+            None => header,
+        })
+    }
+}
+
+// Filters for inputs whose selected part is (or, if `negate` is set, is
+// not) contained in a set
+#[derive(Debug)]
+pub struct FilterIn {
+    set: HashSet<String>,
+    selector: Selector,
+    negate: bool,
+    format: Format,
+}
+impl FilterIn {
+    pub fn new(set: HashSet<String>, selector: Selector, negate: bool, format: Format) -> FilterIn {
+        FilterIn {
+            set,
+            selector,
+            negate,
+            format,
+        }
+    }
+}
+
+impl ProgramAtom for FilterIn {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        let contained = self
+            .selector
+            .get(&arg, meta, self.format)?
+            .is_some_and(|value| self.set.contains(&value));
+        if contained != self.negate {
+            Ok(Output::Resume(arg))
+        } else {
+            Ok(Output::Return(None))
+        }
+    }
+
+    fn header(&mut self, header: Vec<u8>) -> Result<Vec<u8>> {
+        self.selector.resolve(&header, self.format)?;
+        Ok(header)
+    }
+
+    fn uses_captures(&self) -> bool {
+        self.selector.is_capture()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 // possibly unbounded ranges; upper bound is included
 pub struct OpenRange<T> {
     lower_bound: Option<T>,
@@ -165,11 +938,14 @@ impl<T> OpenRange<T> {
             upper_bound,
         }
     }
-    pub fn map<S>(&self, mut f: impl FnMut(&T) -> S) -> OpenRange<S> {
-        OpenRange {
-            lower_bound: self.lower_bound.as_ref().map(&mut f),
-            upper_bound: self.upper_bound.as_ref().map(&mut f),
-        }
+    pub fn try_map<S, E>(
+        &self,
+        mut f: impl FnMut(&T) -> std::result::Result<S, E>,
+    ) -> std::result::Result<OpenRange<S>, E> {
+        Ok(OpenRange {
+            lower_bound: self.lower_bound.as_ref().map(&mut f).transpose()?,
+            upper_bound: self.upper_bound.as_ref().map(&mut f).transpose()?,
+        })
     }
 }
 
@@ -201,6 +977,14 @@ impl LinesAtom {
             Range(range) => range.contains(l),
         }
     }
+
+    // The highest number contained, unless unbounded
+    fn last(&self) -> Option<usize> {
+        match self {
+            LinesAtom::Single(n) => Some(*n),
+            LinesAtom::Range(range) => range.upper_bound,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -222,16 +1006,16 @@ impl ProgramAtom for Lines {
         self.current_line = 0
     }
 
-    fn run(&mut self, arg: String) -> Output {
+    fn run(&mut self, arg: Vec<u8>, _meta: &mut Meta) -> Result<Output> {
         self.current_line += 1;
         if self
             .lines
             .iter()
             .any(|atom| atom.contains(self.current_line))
         {
-            Output::Resume(arg)
+            Ok(Output::Resume(arg))
         } else {
-            Output::Return(None)
+            Ok(Output::Return(None))
         }
     }
 }
@@ -247,11 +1031,11 @@ impl Filter {
 }
 
 impl ProgramAtom for Filter {
-    fn run(&mut self, arg: String) -> Output {
-        if self.regex.is_match(&arg) {
-            Output::Resume(arg)
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        if meta.locate(&self.regex, &arg)? {
+            Ok(Output::Resume(arg))
         } else {
-            Output::Return(None)
+            Ok(Output::Return(None))
         }
     }
 }
@@ -267,6 +1051,7 @@ pub struct FilterRange {
     start: Regex,
     end: Regex,
     state: BlockState,
+    captures: Vec<Capture>, // of the start of the current block
 }
 impl FilterRange {
     pub fn new(start: Regex, end: Regex) -> FilterRange {
@@ -274,26 +1059,39 @@ impl FilterRange {
             start,
             end,
             state: BlockState::Outside,
+            captures: Vec::new(),
         }
     }
 }
 
 impl ProgramAtom for FilterRange {
-    fn run(&mut self, arg: String) -> Output {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
         match self.state {
             BlockState::Outside => {
-                if self.start.is_match(&arg) {
+                if meta.is_match(&self.start, &arg)? {
                     self.state = BlockState::Inside;
-                    Output::ResetAndResume(arg)
+                    // positions are only valid for the start itself
+                    self.captures = meta.captures.clone();
+                    self.captures.iter_mut().for_each(|c| c.range = None);
+                    Ok(Output::ResetAndResume(arg))
                 } else {
-                    Output::Return(None)
+                    Ok(Output::Return(None))
                 }
             }
             BlockState::Inside => {
-                if self.end.is_match(&arg) {
-                    self.state = BlockState::Outside;
+                // the captures of the start remain available in the block,
+                // including its end unless that has named groups of its own
+                meta.captures = self.captures.clone();
+                let end = if self.end.has_names() {
+                    meta.is_match(&self.end, &arg)?
+                } else {
+                    self.end.is_match(&arg)?
+                };
+                if end {
+                    self.state = BlockState::Outside;
+                    return Ok(Output::EndAndResume(arg));
                 };
-                Output::Resume(arg)
+                Ok(Output::Resume(arg))
             }
         }
     }
@@ -308,6 +1106,7 @@ pub struct MatchRange {
     start: Regex,
     end: Regex,
     state: BlockState,
+    captures: Vec<Capture>, // of the start of the current block
 }
 impl MatchRange {
     pub fn new(start: Regex, end: Regex) -> MatchRange {
@@ -315,26 +1114,39 @@ impl MatchRange {
             start,
             end,
             state: BlockState::Outside,
+            captures: Vec::new(),
         }
     }
 }
 
 impl ProgramAtom for MatchRange {
-    fn run(&mut self, arg: String) -> Output {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
         match self.state {
             BlockState::Outside => {
-                if self.start.is_match(&arg) {
+                if meta.is_match(&self.start, &arg)? {
                     self.state = BlockState::Inside;
-                    Output::ResetAndResume(arg)
+                    // positions are only valid for the start itself
+                    self.captures = meta.captures.clone();
+                    self.captures.iter_mut().for_each(|c| c.range = None);
+                    Ok(Output::ResetAndResume(arg))
                 } else {
-                    Output::Return(Some(arg))
+                    Ok(Output::Return(Some(arg)))
                 }
             }
             BlockState::Inside => {
-                if self.end.is_match(&arg) {
+                // the captures of the start remain available in the block,
+                // including its end unless that has named groups of its own
+                meta.captures = self.captures.clone();
+                let end = if self.end.has_names() {
+                    meta.is_match(&self.end, &arg)?
+                } else {
+                    self.end.is_match(&arg)?
+                };
+                if end {
                     self.state = BlockState::Outside;
+                    return Ok(Output::EndAndResume(arg));
                 };
-                Output::Resume(arg)
+                Ok(Output::Resume(arg))
             }
         }
     }
@@ -355,9 +1167,11 @@ impl Enumeration {
 }
 
 impl ProgramAtom for Enumeration {
-    fn run(&mut self, arg: String) -> Output {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
         self.current_line += 1;
-        Output::Resume(format!("{} {}", self.current_line.to_string(), arg))
+        let prefix = format!("{} ", self.current_line);
+        meta.replaced(&[(0..0, 0..prefix.len())]);
+        Ok(Output::Resume([prefix.as_bytes(), &arg].concat()))
     }
 
     fn reset(&mut self) {
@@ -365,59 +1179,748 @@ impl ProgramAtom for Enumeration {
     }
 }
 
-// fn compose<S: Program, T: Program>(first: S, second: T) -> impl Program {
-//     Composition { first, second }
-// }
+// Queues the records emitted by the atoms of a group to be passed on to the
+// atoms after the group, after converting them with `convert`
+fn pend(
+    meta: &mut Meta,
+    emitted: Vec<(Option<Vec<u8>>, Meta)>,
+    mut convert: impl FnMut(Vec<u8>) -> Result<Vec<u8>>,
+) -> Result<()> {
+    for (res, emitted) in emitted {
+        match res {
+            Some(res) => meta.pending.push((convert(res)?, emitted)),
+            None => meta.emitted.push((None, emitted)),
+        }
+    }
+    Ok(())
+}
+
+// Reruns a group of atoms on its input until the result stops changing,
+// but at most `max_iterations` times. Lines appended by the group are only
+// kept from the first run.
+#[derive(Debug)]
+pub struct Repeat {
+    program: Program,
+    max_iterations: usize,
+}
+impl Repeat {
+    pub fn new(program: Program, max_iterations: usize) -> Repeat {
+        Repeat {
+            program,
+            max_iterations,
+        }
+    }
+}
+
+impl ProgramAtom for Repeat {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        // records emitted by the group still have to pass the atoms after it
+        let outer = mem::take(&mut meta.emitted);
+        let mut current = Some(arg);
+        let mut appended = None;
+        for _ in 0..self.max_iterations {
+            let arg = current.take().unwrap();
+            let res = self.program.run(arg.clone(), meta)?;
+            let kept = *appended.get_or_insert(meta.appended.len());
+            meta.appended.truncate(kept);
+            let done = res.as_ref().is_none_or(|res| *res == arg);
+            current = res;
+            if done {
+                break;
+            }
+        }
+        let emitted = mem::replace(&mut meta.emitted, outer);
+        pend(meta, emitted, Ok)?;
+        Ok(match current {
+            Some(res) => Output::Resume(res),
+            None => Output::Return(None),
+        })
+    }
+
+    fn reset(&mut self) {
+        self.program.reset();
+    }
+
+    fn header(&mut self, header: Vec<u8>) -> Result<Vec<u8>> {
+        self.program.header(header)
+    }
+
+    fn uses_captures(&self) -> bool {
+        self.program.uses_captures()
+    }
+
+    fn finish(&mut self, meta: &mut Meta) -> Result<Option<Vec<u8>>> {
+        self.program.finish(meta)?;
+        let emitted = mem::take(&mut meta.emitted);
+        pend(meta, emitted, Ok)?;
+        Ok(None)
+    }
+}
+
+// Runs a group of atoms on the value of a single field and puts the result
+// back in its place. Inputs without that field are passed on unchanged.
+// Values emitted by the group, e.g. by `collect`, are put into the last
+// input that reached the atom.
+#[derive(Debug)]
+pub struct With {
+    field: FieldId,
+    program: Program,
+    format: Format,
+    last: Option<Vec<u8>>,
+}
+impl With {
+    pub fn new(field: FieldId, program: Program, format: Format) -> With {
+        With {
+            field,
+            program,
+            format,
+            last: None,
+        }
+    }
+
+    // Puts a value returned by the group into the field of the record
+    fn set(&self, record: &[u8], value: Vec<u8>) -> Result<Vec<u8>> {
+        let value = text(&value)?.to_owned();
+        let res = self.format.set(text(record)?, &self.field, value)?;
+        Ok(res.into_bytes())
+    }
+}
+
+impl ProgramAtom for With {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        let value = match self.format.get(text(&arg)?, &self.field)? {
+            Some(value) => value,
+            None => return Ok(Output::Resume(arg)),
+        };
+        self.last = Some(arg.clone());
+        // positions can't be tracked through changes of single fields
+        meta.forget_positions();
+        let outer = mem::take(&mut meta.emitted);
+        let res = self.program.run(value.into_bytes(), meta)?;
+        meta.forget_positions();
+        let emitted = mem::replace(&mut meta.emitted, outer);
+        pend(meta, emitted, |value| self.set(&arg, value))?;
+        match res {
+            Some(res) => Ok(Output::Resume(self.set(&arg, res)?)),
+            None => Ok(Output::Return(None)),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.program.reset();
+        self.last = None;
+    }
+
+    fn header(&mut self, header: Vec<u8>) -> Result<Vec<u8>> {
+        self.field.resolve(&self.format.split(text(&header)?)?)?;
+        Ok(header)
+    }
+
+    fn finish(&mut self, meta: &mut Meta) -> Result<Option<Vec<u8>>> {
+        self.program.finish(meta)?;
+        let emitted = mem::take(&mut meta.emitted);
+        if let Some(record) = self.last.take() {
+            pend(meta, emitted, |value| self.set(&record, value))?;
+        }
+        Ok(None)
+    }
+
+    fn uses_captures(&self) -> bool {
+        self.program.uses_captures()
+    }
+}
+
+// Fails unless a regex matches at least one input of the current block, or
+// of the whole input if no block reached the atom
+#[derive(Debug)]
+pub struct Require {
+    regex: Regex,
+    start: Option<usize>, // line of the first input of the current block
+    found: bool,
+    checked: bool, // whether any block of the input was checked
+}
+impl Require {
+    pub fn new(regex: Regex) -> Require {
+        Require {
+            regex,
+            start: None,
+            found: false,
+            checked: false,
+        }
+    }
+}
+
+impl ProgramAtom for Require {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        self.start.get_or_insert(meta.line);
+        if !self.found {
+            self.found = self.regex.is_match(&arg)?;
+        }
+        Ok(Output::Resume(arg))
+    }
+
+    fn reset(&mut self) {
+        self.start = None;
+        self.found = false;
+    }
+
+    fn finish(&mut self, meta: &mut Meta) -> Result<Option<Vec<u8>>> {
+        let found = mem::take(&mut self.found);
+        match self.start.take() {
+            Some(line) => {
+                self.checked = true;
+                if !found {
+                    meta.violations.push(Violation {
+                        line,
+                        message: format!(
+                            "No match of required '{}' in block starting here",
+                            self.regex.as_str()
+                        ),
+                    });
+                }
+            }
+            // nothing at all is no match either
+            None if meta.ended && !self.checked => meta.violations.push(Violation {
+                line: meta.line.max(1),
+                message: format!(
+                    "No match of required '{}' in the input",
+                    self.regex.as_str()
+                ),
+            }),
+            None => (),
+        }
+        if meta.ended {
+            self.checked = false;
+        }
+        Ok(None)
+    }
+}
+
+// Fails if a regex matches any input
+#[derive(Debug)]
+pub struct Forbid {
+    regex: Regex,
+}
+impl Forbid {
+    pub fn new(regex: Regex) -> Forbid {
+        Forbid { regex }
+    }
+}
+
+impl ProgramAtom for Forbid {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        if self.regex.is_match(&arg)? {
+            meta.violations.push(Violation {
+                line: meta.line,
+                message: format!("Match of forbidden '{}'", self.regex.as_str()),
+            });
+        }
+        Ok(Output::Resume(arg))
+    }
+}
+
+// Queues the lines of a file to be printed after the input, like the `r`
+// command of sed; the input is either passed on or replaced by them. The
+// file is read when the atom is reached for the first time.
+#[derive(Debug)]
+pub struct ReadFile {
+    path: String,
+    lines: Option<Vec<Vec<u8>>>,
+    replace: bool,
+}
+impl ReadFile {
+    pub fn new(path: String, replace: bool) -> ReadFile {
+        ReadFile {
+            path,
+            lines: None,
+            replace,
+        }
+    }
+}
+
+impl ProgramAtom for ReadFile {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        let lines = match &self.lines {
+            Some(lines) => lines,
+            None => {
+                let content = std::fs::read(&self.path)
+                    .with_context(|| format!("Could not read {}", self.path))?;
+                self.lines.insert(lines(&content))
+            }
+        };
+        meta.appended.extend(lines.iter().cloned());
+        if self.replace {
+            Ok(Output::Return(None))
+        } else {
+            Ok(Output::Resume(arg))
+        }
+    }
+}
+
+// Splits like `str::lines`, i.e. a final line break doesn't start another
+// line and the `\r` of a `\r\n` is removed
+fn lines(content: &[u8]) -> Vec<Vec<u8>> {
+    if content.is_empty() {
+        return Vec::new();
+    }
+    let content = content.strip_suffix(b"\n").unwrap_or(content);
+    content
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line).to_vec())
+        .collect()
+}
+
+fn create(path: &path::Path) -> Result<BufWriter<File>> {
+    let file =
+        File::create(path).with_context(|| format!("Could not create {}", path.display()))?;
+    Ok(BufWriter::new(file))
+}
+
+fn append(path: &path::Path) -> Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Could not open {}", path.display()))?;
+    Ok(BufWriter::new(file))
+}
+
+// Writes `text` the way it is printed, with the line breaks and terminator
+// of its record, which default to `\n`
+fn write_record(file: &mut impl Write, text: &[u8], meta: &Meta) -> Result<()> {
+    let line_break = match meta.line_break {
+        "" => "\n",
+        line_break => line_break,
+    };
+    if line_break == "\n" {
+        file.write_all(text)?;
+    } else {
+        let lines: Vec<_> = text.split(|&b| b == b'\n').collect();
+        file.write_all(&lines.join(line_break.as_bytes()))?;
+    }
+    match meta.terminator {
+        "" => file.write_all(line_break.as_bytes())?,
+        terminator => file.write_all(terminator.as_bytes())?,
+    }
+    Ok(())
+}
+
+// Writes every input to a file and passes it on. As in sed, the file is
+// truncated when it is opened for the first input.
+#[derive(Debug)]
+pub struct WriteFile {
+    path: String,
+    file: Option<BufWriter<File>>,
+}
+impl WriteFile {
+    pub fn new(path: String) -> WriteFile {
+        WriteFile { path, file: None }
+    }
+}
+
+impl ProgramAtom for WriteFile {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(create(path::Path::new(&self.path))?),
+        };
+        write_record(file, &arg, meta)?;
+        Ok(Output::Resume(arg))
+    }
+
+    fn finish(&mut self, _meta: &mut Meta) -> Result<Option<Vec<u8>>> {
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+        }
+        Ok(None)
+    }
+}
+
+// Number of files `SplitTo` keeps open at once
+const MAX_OPEN_FILES: usize = 128;
+
+// Like `WriteFile`, but the path is expanded from a template referencing
+// named captures, so that inputs can be split into several files. Captured
+// values can't leave the directory given by the template. Once too many
+// files are open, the least recently used one is closed, to be reopened for
+// appending when needed again.
+#[derive(Debug)]
+pub struct SplitTo {
+    template: Replacement,
+    files: HashMap<PathBuf, (usize, BufWriter<File>)>, // by time of last use
+    created: HashSet<PathBuf>,
+    time: usize,
+}
+impl SplitTo {
+    pub fn new(template: Replacement) -> SplitTo {
+        SplitTo {
+            template,
+            files: HashMap::new(),
+            created: HashSet::new(),
+            time: 0,
+        }
+    }
+
+    fn path(&self, arg: &[u8], meta: &Meta) -> Result<PathBuf> {
+        for name in self.template.names() {
+            let value = match meta.capture(name) {
+                Some(capture) => &capture.value,
+                None => bail!(
+                    "No value captured as '{}' for the file name of input: {}",
+                    name,
+                    String::from_utf8_lossy(arg)
+                ),
+            };
+            if value.contains(&b'/')
+                || value.contains(&b'\0')
+                || value.windows(2).any(|w| w == b"..")
+            {
+                bail!(
+                    "Captured '{}' can't be used as part of a file name for input: {}",
+                    String::from_utf8_lossy(value),
+                    String::from_utf8_lossy(arg)
+                );
+            }
+        }
+        let mut path = Vec::new();
+        self.template
+            .expand_named(|name| Some(meta.capture(name)?.value.as_slice()), &mut path);
+        if path.is_empty() {
+            bail!(
+                "File name expanded to nothing for input: {}",
+                String::from_utf8_lossy(arg)
+            );
+        }
+        Ok(exec::os_string(path).into())
+    }
+
+    fn close_least_recent(&mut self) -> Result<()> {
+        let least = self.files.iter().min_by_key(|(_, (time, _))| *time);
+        if let Some(path) = least.map(|(path, _)| path.clone()) {
+            let (_, mut file) = self.files.remove(&path).unwrap();
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl ProgramAtom for SplitTo {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        let path = self.path(&arg, meta)?;
+        if !self.files.contains_key(&path) && self.files.len() >= MAX_OPEN_FILES {
+            self.close_least_recent()?;
+        }
+        self.time += 1;
+        let (time, file) = match self.files.entry(path) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file = if self.created.contains(entry.key()) {
+                    append(entry.key())?
+                } else {
+                    self.created.insert(entry.key().clone());
+                    create(entry.key())?
+                };
+                entry.insert((self.time, file))
+            }
+        };
+        *time = self.time;
+        write_record(file, &arg, meta)?;
+        Ok(Output::Resume(arg))
+    }
+
+    fn finish(&mut self, _meta: &mut Meta) -> Result<Option<Vec<u8>>> {
+        for (_, file) in self.files.values_mut() {
+            file.flush()?;
+        }
+        Ok(None)
+    }
+
+    fn uses_captures(&self) -> bool {
+        self.template.has_names()
+    }
+}
+
+// Inputs of the current block held back by an atom to be emitted at once
+#[derive(Debug, Default)]
+struct Block {
+    inputs: Vec<Vec<u8>>,
+    start: Option<Meta>, // of the first input
+}
+impl Block {
+    fn push(&mut self, arg: Vec<u8>, meta: &Meta) {
+        if self.start.is_none() {
+            self.start = Some(meta.for_emitted());
+        }
+        self.inputs.push(arg);
+    }
+
+    // Empties the block and returns its inputs, unless there are none; the
+    // result is attributed to the first input by setting `meta`
+    fn take(&mut self, meta: &mut Meta) -> Option<Vec<Vec<u8>>> {
+        *meta = self.start.take()?;
+        Some(mem::take(&mut self.inputs))
+    }
+}
+
+// Joins all inputs of the current block into a single record, which is
+// emitted when the block ends
+#[derive(Debug)]
+pub struct Collect {
+    separator: Vec<u8>,
+    block: Block,
+}
+impl Collect {
+    pub fn new(separator: Vec<u8>) -> Collect {
+        Collect {
+            separator,
+            block: Block::default(),
+        }
+    }
+}
+
+impl ProgramAtom for Collect {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        self.block.push(arg, meta);
+        Ok(Output::Return(None))
+    }
+
+    fn reset(&mut self) {
+        self.block = Block::default();
+    }
+
+    fn finish(&mut self, meta: &mut Meta) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .block
+            .take(meta)
+            .map(|inputs| inputs.join(self.separator.as_slice())))
+    }
+}
+
+// Pipes each input, or with `per_block` all inputs of the current block at
+// once, to a shell command and replaces them by its output. Captures
+// referenced in the command are quoted for the shell.
+#[derive(Debug)]
+pub struct Exec {
+    command: Replacement,
+    per_block: bool,
+    timeout: Option<Duration>,
+    block: Block,
+}
+impl Exec {
+    pub fn new(command: Replacement, per_block: bool, timeout: Option<Duration>) -> Exec {
+        Exec {
+            command,
+            per_block,
+            timeout,
+            block: Block::default(),
+        }
+    }
+
+    fn command(&self, meta: &Meta) -> Vec<u8> {
+        let quoted: Vec<_> = meta
+            .captures
+            .iter()
+            .map(|c| (c.name.as_str(), exec::quote(&c.value)))
+            .collect();
+        let mut command = Vec::new();
+        self.command.expand_named(
+            |name| {
+                let (_, value) = quoted.iter().find(|(n, _)| *n == name)?;
+                Some(value.as_slice())
+            },
+            &mut command,
+        );
+        command
+    }
+}
+
+impl ProgramAtom for Exec {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        if self.per_block {
+            self.block.push(arg, meta);
+            return Ok(Output::Return(None));
+        }
+        let mut input = arg;
+        input.push(b'\n');
+        let res =
+            exec::run(&self.command(meta), input, self.timeout).map_err(at_line(meta.line))?;
+        meta.forget_positions();
+        Ok(Output::Resume(res))
+    }
+
+    fn reset(&mut self) {
+        self.block = Block::default();
+    }
+
+    fn finish(&mut self, meta: &mut Meta) -> Result<Option<Vec<u8>>> {
+        let mut input = match self.block.take(meta) {
+            Some(inputs) => inputs.join(&b'\n'),
+            None => return Ok(None),
+        };
+        input.push(b'\n');
+        let res =
+            exec::run(&self.command(meta), input, self.timeout).map_err(at_line(meta.line))?;
+        Ok(Some(res))
+    }
+
+    fn uses_captures(&self) -> bool {
+        self.command.has_names()
+    }
+}
 
 #[derive(Debug)]
 pub struct Sub {
     regex: Regex,
-    replacement: String,
+    replacement: Replacement,
+    only_replaced: bool,
 }
 impl Sub {
-    pub fn new(regex: Regex, replacement: String) -> Sub {
-        Sub { regex, replacement }
+    pub fn new(regex: Regex, replacement: Replacement, only_replaced: bool) -> Sub {
+        Sub {
+            regex,
+            replacement,
+            only_replaced,
+        }
     }
 }
 
 impl ProgramAtom for Sub {
-    fn run(&mut self, arg: String) -> Output {
-        let res = self.regex.replace(&arg, &self.replacement);
-        Output::Resume(res.into_owned())
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        substitute(
+            &self.regex,
+            &self.replacement,
+            arg,
+            meta,
+            self.only_replaced,
+            |n| n == 1,
+            Some(1),
+        )
     }
 }
 
 #[derive(Debug)]
 pub struct Gsub {
     regex: Regex,
-    replacement: String,
+    replacement: Replacement,
+    only_replaced: bool,
 }
 impl Gsub {
-    pub fn new(regex: Regex, replacement: String) -> Gsub {
-        Gsub { regex, replacement }
+    pub fn new(regex: Regex, replacement: Replacement, only_replaced: bool) -> Gsub {
+        Gsub {
+            regex,
+            replacement,
+            only_replaced,
+        }
     }
 }
 
 impl ProgramAtom for Gsub {
-    fn run(&mut self, arg: String) -> Output {
-        let res = self.regex.replace_all(&arg, &self.replacement);
-        Output::Resume(res.into_owned())
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        substitute(
+            &self.regex,
+            &self.replacement,
+            arg,
+            meta,
+            self.only_replaced,
+            |_| true,
+            None,
+        )
     }
 }
 
-// pub fn make_filter_sub(regex: Regex, replacement: String) -> impl Program {
-//     compose(make_filter(regex.clone()), make_sub(regex, replacement))
-// }
+// Replaces only the occurrences selected by a list of `LinesAtom`s
+#[derive(Debug)]
+pub struct SubNth {
+    regex: Regex,
+    replacement: Replacement,
+    occurrences: Vec<LinesAtom>,
+    last: Option<usize>, // occurrence that can be selected, if bounded
+    only_replaced: bool,
+}
+impl SubNth {
+    pub fn new(
+        occurrences: Vec<LinesAtom>,
+        regex: Regex,
+        replacement: Replacement,
+        only_replaced: bool,
+    ) -> SubNth {
+        let last = occurrences
+            .iter()
+            .map(LinesAtom::last)
+            .try_fold(0, |max, last| Some(max.max(last?)));
+        SubNth {
+            regex,
+            replacement,
+            occurrences,
+            last,
+            only_replaced,
+        }
+    }
+}
+
+impl ProgramAtom for SubNth {
+    fn run(&mut self, arg: Vec<u8>, meta: &mut Meta) -> Result<Output> {
+        let occurrences = &self.occurrences;
+        substitute(
+            &self.regex,
+            &self.replacement,
+            arg,
+            meta,
+            self.only_replaced,
+            |n| occurrences.iter().any(|atom| atom.contains(n)),
+            self.last,
+        )
+    }
+}
 
-// pub fn make_filter_gsub(regex: Regex, replacement: String) -> impl Program {
-//     compose(make_filter(regex.clone()), make_gsub(regex, replacement))
-// }
+// If `only_replaced` is set, inputs in which nothing was replaced are dropped;
+// occurrences after `until` are never selected
+fn substitute(
+    regex: &Regex,
+    replacement: &Replacement,
+    arg: Vec<u8>,
+    meta: &mut Meta,
+    only_replaced: bool,
+    select: impl FnMut(usize) -> bool,
+    until: Option<usize>,
+) -> Result<Output> {
+    let mut first = None;
+    let mut edits = Vec::new();
+    let track = meta.track.captures;
+    let replaced = |caps: &Captures, new: Range<usize>| {
+        if first.is_none() && track {
+            first = Some(regex.named_captures(caps));
+        }
+        // the whole match is always present
+        edits.push((caps.get(0).unwrap().range(), new));
+    };
+    let res = match regex.replace_selected(&arg, replacement, select, until, replaced)? {
+        Some(res) => res,
+        None if only_replaced => return Ok(Output::Return(None)),
+        None => return Ok(Output::Resume(arg)),
+    };
+
+    if track && regex.has_names() {
+        // replaced at least once, so `first` is set
+        meta.captures = first.unwrap_or_default();
+    }
+    meta.replaced(&edits);
+    meta.spans.extend(
+        edits
+            .into_iter()
+            .filter(|(_, new)| !new.is_empty())
+            .map(|(_, new)| (new, Highlight::Insertion)),
+    );
+    Ok(Output::Resume(res))
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::engine::Engine;
+    use crate::parse::parse_replacement;
 
-    static INI_FILE: &'static str = "
+    #[allow(dead_code)] // the lines below, for reference
+    static INI_FILE: &str = "
 
     [Header 1]
     key1 = header1_value1
@@ -428,14 +1931,46 @@ mod tests {
     key2 = header2_value2\
     ";
 
-    static LINE1: &'static str = "";
-    static LINE2: &'static str = "[Header 1]";
-    static LINE3: &'static str = "key1 = header1_value1";
-    static LINE4: &'static str = "key2 = header1_value2";
-    static LINE5: &'static str = "";
-    static LINE6: &'static str = "[Header 2]";
-    static LINE7: &'static str = "key1 = header2_value1";
-    static LINE8: &'static str = "key2 = header2_value2";
+    static LINE1: &str = "";
+    static LINE2: &str = "[Header 1]";
+    static LINE3: &str = "key1 = header1_value1";
+    static LINE4: &str = "key2 = header1_value2";
+    static LINE5: &str = "";
+    static LINE6: &str = "[Header 2]";
+    static LINE7: &str = "key1 = header2_value1";
+    static LINE8: &str = "key2 = header2_value2";
+    static LINES: [&str; 8] = [LINE1, LINE2, LINE3, LINE4, LINE5, LINE6, LINE7, LINE8];
+
+    // Runs `lines` through `pr` like `main` does, numbering them from 1.
+    // Returns the records in the order they are printed, along with their
+    // meta, and the failed checks.
+    fn run_lines(pr: &mut Program, lines: &[&str]) -> (Vec<(String, Meta)>, Vec<Violation>) {
+        let mut printed = Vec::new();
+        let mut violations = Vec::new();
+        let mut take = |mut meta: Meta, out: Option<Vec<u8>>| {
+            violations.append(&mut meta.violations);
+            for (emitted, meta) in mem::take(&mut meta.emitted) {
+                printed.extend(emitted.map(|text| (String::from_utf8(text).unwrap(), meta)));
+            }
+            printed.extend(out.map(|text| (String::from_utf8(text).unwrap(), meta)));
+        };
+        for (n, line) in lines.iter().enumerate() {
+            let mut meta = Meta {
+                line: n + 1,
+                ..Meta::default()
+            };
+            let out = pr.run(line.as_bytes().to_vec(), &mut meta).unwrap();
+            take(meta, out);
+        }
+        let mut meta = Meta {
+            line: lines.len(),
+            ended: true,
+            ..Meta::default()
+        };
+        pr.finish(&mut meta).unwrap();
+        take(meta, None);
+        (printed, violations)
+    }
 
     #[test]
     fn test_make_enumerate() {
@@ -443,17 +1978,41 @@ mod tests {
 
         let mut pr = Enumeration::new();
 
-        assert_eq!(pr.run(LINE1.to_owned()), Resume(String::from("1 ") + LINE1));
-        assert_eq!(pr.run(LINE2.to_owned()), Resume(String::from("2 ") + LINE2));
-        assert_eq!(pr.run(LINE3.to_owned()), Resume(String::from("3 ") + LINE3));
-        assert_eq!(pr.run(LINE4.to_owned()), Resume(String::from("4 ") + LINE4));
+        assert_eq!(
+            pr.run(LINE1.into(), &mut Meta::default()).unwrap(),
+            Resume(format!("1 {}", LINE1).into())
+        );
+        assert_eq!(
+            pr.run(LINE2.into(), &mut Meta::default()).unwrap(),
+            Resume(format!("2 {}", LINE2).into())
+        );
+        assert_eq!(
+            pr.run(LINE3.into(), &mut Meta::default()).unwrap(),
+            Resume(format!("3 {}", LINE3).into())
+        );
+        assert_eq!(
+            pr.run(LINE4.into(), &mut Meta::default()).unwrap(),
+            Resume(format!("4 {}", LINE4).into())
+        );
 
         pr.reset();
 
-        assert_eq!(pr.run(LINE5.to_owned()), Resume(String::from("1 ") + LINE5));
-        assert_eq!(pr.run(LINE6.to_owned()), Resume(String::from("2 ") + LINE6));
-        assert_eq!(pr.run(LINE7.to_owned()), Resume(String::from("3 ") + LINE7));
-        assert_eq!(pr.run(LINE8.to_owned()), Resume(String::from("4 ") + LINE8));
+        assert_eq!(
+            pr.run(LINE5.into(), &mut Meta::default()).unwrap(),
+            Resume(format!("1 {}", LINE5).into())
+        );
+        assert_eq!(
+            pr.run(LINE6.into(), &mut Meta::default()).unwrap(),
+            Resume(format!("2 {}", LINE6).into())
+        );
+        assert_eq!(
+            pr.run(LINE7.into(), &mut Meta::default()).unwrap(),
+            Resume(format!("3 {}", LINE7).into())
+        );
+        assert_eq!(
+            pr.run(LINE8.into(), &mut Meta::default()).unwrap(),
+            Resume(format!("4 {}", LINE8).into())
+        );
     }
 
     #[test]
@@ -465,14 +2024,38 @@ mod tests {
             Regex::new(r"^\[").unwrap(),
         );
 
-        assert_eq!(pr.run(LINE1.to_owned()), Return(None));
-        assert_eq!(pr.run(LINE2.to_owned()), Resume(LINE2.to_owned()));
-        assert_eq!(pr.run(LINE3.to_owned()), Resume(LINE3.to_owned()));
-        assert_eq!(pr.run(LINE4.to_owned()), Resume(LINE4.to_owned()));
-        assert_eq!(pr.run(LINE5.to_owned()), Resume(LINE5.to_owned()));
-        assert_eq!(pr.run(LINE6.to_owned()), Resume(LINE6.to_owned()));
-        assert_eq!(pr.run(LINE7.to_owned()), Return(None));
-        assert_eq!(pr.run(LINE8.to_owned()), Return(None));
+        assert_eq!(
+            pr.run(LINE1.into(), &mut Meta::default()).unwrap(),
+            Return(None)
+        );
+        assert_eq!(
+            pr.run(LINE2.into(), &mut Meta::default()).unwrap(),
+            ResetAndResume(LINE2.into())
+        );
+        assert_eq!(
+            pr.run(LINE3.into(), &mut Meta::default()).unwrap(),
+            Resume(LINE3.into())
+        );
+        assert_eq!(
+            pr.run(LINE4.into(), &mut Meta::default()).unwrap(),
+            Resume(LINE4.into())
+        );
+        assert_eq!(
+            pr.run(LINE5.into(), &mut Meta::default()).unwrap(),
+            Resume(LINE5.into())
+        );
+        assert_eq!(
+            pr.run(LINE6.into(), &mut Meta::default()).unwrap(),
+            EndAndResume(LINE6.into())
+        );
+        assert_eq!(
+            pr.run(LINE7.into(), &mut Meta::default()).unwrap(),
+            Return(None)
+        );
+        assert_eq!(
+            pr.run(LINE8.into(), &mut Meta::default()).unwrap(),
+            Return(None)
+        );
     }
 
     #[test]
@@ -484,14 +2067,38 @@ mod tests {
             Regex::new(r"^\[").unwrap(),
         );
 
-        assert_eq!(pr.run(LINE1.to_owned()), Return(Some(LINE1.to_owned())));
-        assert_eq!(pr.run(LINE2.to_owned()), Resume(LINE2.to_owned()));
-        assert_eq!(pr.run(LINE3.to_owned()), Resume(LINE3.to_owned()));
-        assert_eq!(pr.run(LINE4.to_owned()), Resume(LINE4.to_owned()));
-        assert_eq!(pr.run(LINE5.to_owned()), Resume(LINE5.to_owned()));
-        assert_eq!(pr.run(LINE6.to_owned()), Resume(LINE6.to_owned()));
-        assert_eq!(pr.run(LINE7.to_owned()), Return(Some(LINE7.to_owned())));
-        assert_eq!(pr.run(LINE8.to_owned()), Return(Some(LINE8.to_owned())));
+        assert_eq!(
+            pr.run(LINE1.into(), &mut Meta::default()).unwrap(),
+            Return(Some(LINE1.into()))
+        );
+        assert_eq!(
+            pr.run(LINE2.into(), &mut Meta::default()).unwrap(),
+            ResetAndResume(LINE2.into())
+        );
+        assert_eq!(
+            pr.run(LINE3.into(), &mut Meta::default()).unwrap(),
+            Resume(LINE3.into())
+        );
+        assert_eq!(
+            pr.run(LINE4.into(), &mut Meta::default()).unwrap(),
+            Resume(LINE4.into())
+        );
+        assert_eq!(
+            pr.run(LINE5.into(), &mut Meta::default()).unwrap(),
+            Resume(LINE5.into())
+        );
+        assert_eq!(
+            pr.run(LINE6.into(), &mut Meta::default()).unwrap(),
+            EndAndResume(LINE6.into())
+        );
+        assert_eq!(
+            pr.run(LINE7.into(), &mut Meta::default()).unwrap(),
+            Return(Some(LINE7.into()))
+        );
+        assert_eq!(
+            pr.run(LINE8.into(), &mut Meta::default()).unwrap(),
+            Return(Some(LINE8.into()))
+        );
     }
 
     #[test]
@@ -500,9 +2107,12 @@ mod tests {
 
         // Example from the 'regex' docs
         let re = Regex::new(r"(?P<y>\d{4})-(?P<m>\d{2})-(?P<d>\d{2})").unwrap();
-        let s = "2012-03-14 and 2014-07-05".to_owned();
-        let mut pr = Gsub::new(re, "$m/$d/$y".to_owned());
-        assert_eq!(pr.run(s), Resume("03/14/2012 and 07/05/2014".to_owned()));
+        let s = "2012-03-14 and 2014-07-05".into();
+        let mut pr = Gsub::new(re, parse_replacement("$m/$d/$y").unwrap(), false);
+        assert_eq!(
+            pr.run(s, &mut Meta::default()).unwrap(),
+            Resume("03/14/2012 and 07/05/2014".into())
+        );
     }
 
     #[test]
@@ -511,44 +2121,992 @@ mod tests {
 
         // Example from the 'regex' docs
         let re = Regex::new("[^01]+").unwrap();
-        let mut pr = Sub::new(re, "".to_owned());
-        assert_eq!(pr.run("1078910a".to_owned()), Resume("1010a".to_owned()));
+        let mut pr = Sub::new(re, parse_replacement("").unwrap(), false);
+        assert_eq!(
+            pr.run("1078910a".into(), &mut Meta::default()).unwrap(),
+            Resume("1010a".into())
+        );
 
         let re = Regex::new("^abc").unwrap();
-        let mut pr = Sub::new(re, "".to_owned());
-        assert_eq!(pr.run("def".to_owned()), Resume("def".to_owned()));
+        let mut pr = Sub::new(re, parse_replacement("").unwrap(), false);
+        assert_eq!(
+            pr.run("def".into(), &mut Meta::default()).unwrap(),
+            Resume("def".into())
+        );
+    }
+
+    #[test]
+    fn test_make_sub_case_conversion() {
+        use Output::*;
+
+        let re = Regex::new(r"^(\w+) = (\w+)").unwrap();
+        let rep = parse_replacement(r"\U$1\E = \u$2").unwrap();
+        let mut pr = Sub::new(re, rep, false);
+        assert_eq!(
+            pr.run(LINE3.into(), &mut Meta::default()).unwrap(),
+            Resume("KEY1 = Header1_value1".into())
+        );
+
+        let re = Regex::new(r"(?P<word>\w+)").unwrap();
+        let rep = parse_replacement(r"\L\u${word}\\").unwrap();
+        let mut pr = Gsub::new(re, rep, false);
+        assert_eq!(
+            pr.run("HELLO wORLD".into(), &mut Meta::default()).unwrap(),
+            Resume(r"Hello\ World\".into())
+        );
+    }
+
+    #[test]
+    fn test_make_sub_escapes() {
+        use Output::*;
+
+        let re = Regex::new(" = ").unwrap();
+        let rep = parse_replacement(r"\t\x3d\u{2192}\n").unwrap();
+        let mut pr = Sub::new(re.clone(), rep, false);
+        assert_eq!(
+            pr.run(LINE3.into(), &mut Meta::default()).unwrap(),
+            Resume("key1\t=\u{2192}\nheader1_value1".into())
+        );
+        assert_eq!(
+            parse_replacement(r"\x7e\u{e9}").unwrap(),
+            parse_replacement("~\u{e9}").unwrap()
+        );
+
+        let mut pr = Sub::new(re, parse_replacement(r"\xe9").unwrap(), false);
+        assert_eq!(
+            pr.run(LINE3.into(), &mut Meta::default()).unwrap(),
+            Resume(b"key1\xe9header1_value1".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_make_sub_nth() {
+        use Output::*;
+
+        let re = Regex::new("a").unwrap();
+        let mut pr = SubNth::new(
+            vec![LinesAtom::Single(2)],
+            re,
+            parse_replacement("x").unwrap(),
+            false,
+        );
+        assert_eq!(pr.last, Some(2));
+        assert_eq!(
+            pr.run("aaaa".into(), &mut Meta::default()).unwrap(),
+            Resume("axaa".into())
+        );
+        assert_eq!(
+            pr.run("a".into(), &mut Meta::default()).unwrap(),
+            Resume("a".into())
+        );
+
+        let re = Regex::new("a").unwrap();
+        let range = OpenRange::new(Some(3), None);
+        let mut pr = SubNth::new(
+            vec![LinesAtom::Range(range)],
+            re,
+            parse_replacement("x").unwrap(),
+            true,
+        );
+        assert_eq!(pr.last, None);
+        assert_eq!(
+            pr.run("aaaa".into(), &mut Meta::default()).unwrap(),
+            Resume("aaxx".into())
+        );
+        assert_eq!(
+            pr.run("aa".into(), &mut Meta::default()).unwrap(),
+            Return(None)
+        );
+    }
+
+    #[test]
+    fn test_make_filter_sub() {
+        use Output::*;
+
+        let re = Regex::new("^key").unwrap();
+        let mut pr = Sub::new(re, parse_replacement("name").unwrap(), true);
+        assert_eq!(
+            pr.run(LINE3.into(), &mut Meta::default()).unwrap(),
+            Resume("name1 = header1_value1".into())
+        );
+        assert_eq!(
+            pr.run(LINE2.into(), &mut Meta::default()).unwrap(),
+            Return(None)
+        );
+
+        let re = Regex::new("").unwrap();
+        let mut pr = Gsub::new(re, parse_replacement("-").unwrap(), true);
+        assert_eq!(
+            pr.run("ab".into(), &mut Meta::default()).unwrap(),
+            Resume("-a-b-".into())
+        );
+    }
+
+    #[test]
+    fn test_repeat() {
+        use Output::*;
+
+        let re = Regex::new(r"(\d)(\d{3})\b").unwrap();
+        let sub = Sub::new(re, parse_replacement("$1,$2").unwrap(), false);
+        let mut pr = Repeat::new(Program::new(vec![sub.into()]), 100);
+        assert_eq!(
+            pr.run("1234567".into(), &mut Meta::default()).unwrap(),
+            Resume("1,234,567".into())
+        );
+
+        let re = Regex::new("^").unwrap();
+        let sub = Sub::new(re, parse_replacement("x").unwrap(), false);
+        let mut pr = Repeat::new(Program::new(vec![sub.into()]), 3);
+        assert_eq!(
+            pr.run("".into(), &mut Meta::default()).unwrap(),
+            Resume("xxx".into())
+        );
+
+        let filter = Filter::new(Regex::new("^key").unwrap());
+        let mut pr = Repeat::new(Program::new(vec![filter.into()]), 3);
+        assert_eq!(
+            pr.run(LINE2.into(), &mut Meta::default()).unwrap(),
+            Return(None)
+        );
+    }
+
+    #[test]
+    fn test_bytes() {
+        use Output::*;
+
+        // regexes match the text of valid UTF-8 and any bytes otherwise
+        let re = Regex::new(r"é|(?-u:\xff)").unwrap();
+        let mut pr = Gsub::new(re, parse_replacement("?").unwrap(), false);
+        assert_eq!(
+            pr.run(b"caf\xc3\xa9 \xff\xfe".to_vec(), &mut Meta::default())
+                .unwrap(),
+            Resume(b"caf? ?\xfe".to_vec())
+        );
+
+        // case conversions keep invalid bytes
+        let re = Regex::new(r"(?-u:.+)").unwrap();
+        let mut pr = Sub::new(re, parse_replacement(r"\U$0").unwrap(), false);
+        assert_eq!(
+            pr.run(b"\xc3\xa9a\xffb".to_vec(), &mut Meta::default())
+                .unwrap(),
+            Resume(b"\xc3\x89A\xffB".to_vec())
+        );
+
+        // fields can only be taken from text
+        let mut pr = Fields::new(
+            vec![FieldsAtom::Single(FieldId::Int(1))],
+            Format::Plain,
+            Format::Plain,
+        );
+        let err = pr
+            .run(b"a\xff b".to_vec(), &mut Meta::default())
+            .unwrap_err();
+        assert!(err.downcast::<InvalidText>().is_ok());
     }
 
     #[test]
     fn test_make_match() {
         let mut pr = Match::new(Regex::new("^x: .*").unwrap());
 
-        let string1 = "x: test1".to_owned();
-        let string2 = "yx: test2".to_owned();
-        assert_eq!(pr.run(string1.clone()), Output::Resume(string1));
-        assert_eq!(pr.run(string2.clone()), Output::Return(Some(string2)));
+        let string1: Vec<u8> = "x: test1".into();
+        let string2: Vec<u8> = "yx: test2".into();
+        assert_eq!(
+            pr.run(string1.clone(), &mut Meta::default()).unwrap(),
+            Output::Resume(string1)
+        );
+        assert_eq!(
+            pr.run(string2.clone(), &mut Meta::default()).unwrap(),
+            Output::Return(Some(string2))
+        );
     }
 
     #[test]
     fn test_make_filter() {
         let mut pr = Filter::new(Regex::new("^x: .*").unwrap());
 
-        let string1 = "x: test1".to_owned();
-        let string2 = "yx: test2".to_owned();
+        let string1: Vec<u8> = "x: test1".into();
+        let string2: Vec<u8> = "yx: test2".into();
 
-        assert_eq!(pr.run(string1.clone()), Output::Resume(string1));
-        assert_eq!(pr.run(string2.clone()), Output::Return(None));
+        assert_eq!(
+            pr.run(string1.clone(), &mut Meta::default()).unwrap(),
+            Output::Resume(string1)
+        );
+        assert_eq!(
+            pr.run(string2.clone(), &mut Meta::default()).unwrap(),
+            Output::Return(None)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "fancy-regex")]
+    fn test_make_filter_lookaround() {
+        let mut pr = Filter::new(Regex::new(r"^(\w+) = (?!\1)").unwrap());
+
+        let string1: Vec<u8> = "key1 = header1_value1".into();
+        let string2: Vec<u8> = "key1 = key1_value1".into();
+
+        assert_eq!(
+            pr.run(string1.clone(), &mut Meta::default()).unwrap(),
+            Output::Resume(string1)
+        );
+        assert_eq!(
+            pr.run(string2, &mut Meta::default()).unwrap(),
+            Output::Return(None)
+        );
+    }
+
+    #[test]
+    fn test_engine_prefix() {
+        let lookaround = r"^(\w+) = (?!\1)";
+        assert!(Regex::with_engine(&format!("(*REGEX){}", lookaround), Engine::Fancy).is_err());
+        assert_eq!(
+            Regex::with_engine(&format!("(*FANCY){}", lookaround), Engine::Regex).is_ok(),
+            cfg!(feature = "fancy-regex")
+        );
+        let re = Regex::with_engine("(*REGEX)^a", Engine::default()).unwrap();
+        assert_eq!(re.as_str(), "^a");
+        assert!(re.is_match(b"ab").unwrap());
     }
 
     #[test]
     fn test_fields() {
-        let mut pr = Fields::new(vec![
-            FieldsAtom::Range(FieldId::Int(3), FieldId::FromLast(2)),
-            FieldsAtom::Single(FieldId::Int(1)),
+        let mut pr = Fields::new(
+            vec![
+                FieldsAtom::Range(OpenRange::new(
+                    Some(FieldId::Int(3)),
+                    Some(FieldId::FromLast(2)),
+                )),
+                FieldsAtom::Single(FieldId::Int(1)),
+            ],
+            Format::Plain,
+            Format::Plain,
+        );
+
+        let string1: Vec<u8> = "1 2 3 4 5 6 7".into();
+        let string2: Vec<u8> = "1 3 4 5 6".into();
+        assert_eq!(
+            pr.run(string1, &mut Meta::default()).unwrap(),
+            Output::Resume(string2)
+        );
+    }
+
+    #[test]
+    fn test_fields_csv_header() {
+        let mut pr = Fields::new(
+            vec![
+                FieldsAtom::Single(FieldId::Name("note".to_owned())),
+                FieldsAtom::Single(FieldId::Int(1)),
+            ],
+            Format::Delimited(','),
+            Format::Delimited(','),
+        );
+
+        let header = "name,email,note".into();
+        assert_eq!(pr.header(header).unwrap(), b"name,note");
+
+        let string1: Vec<u8> = r#""Doe, John",john@x.org,"said ""hi""""#.into();
+        let string2: Vec<u8> = r#""Doe, John","said ""hi""""#.into();
+        assert_eq!(
+            pr.run(string1, &mut Meta::default()).unwrap(),
+            Output::Resume(string2)
+        );
+        assert!(pr
+            .run(r#"Doe,john@x.org,"said"#.into(), &mut Meta::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_where() {
+        let mut pr = Where::new(
+            FieldId::Name("email".to_owned()),
+            Regex::new(r"@x\.org$").unwrap(),
+            Format::Delimited('\t'),
+        );
+        assert!(pr.run("x".into(), &mut Meta::default()).is_err());
+
+        pr.header("name\temail".into()).unwrap();
+        let string1: Vec<u8> = "John\tjohn@x.org".into();
+        let string2: Vec<u8> = "Jane\tjane@y.org".into();
+        assert_eq!(
+            pr.run(string1.clone(), &mut Meta::default()).unwrap(),
+            Output::Resume(string1)
+        );
+        assert_eq!(
+            pr.run(string2, &mut Meta::default()).unwrap(),
+            Output::Return(None)
+        );
+    }
+
+    #[test]
+    fn test_fields_json() {
+        let path = |segments| FieldsAtom::Single(FieldId::Path(Path::new(segments)));
+        let fields = || {
+            vec![
+                path(vec![
+                    Segment::Key("request".to_owned()),
+                    Segment::Key("method".to_owned()),
+                ]),
+                path(vec![Segment::Key("tags".to_owned()), Segment::Index(1)]),
+                FieldsAtom::Single(FieldId::Name("status".to_owned())),
+                FieldsAtom::Single(FieldId::Name("missing".to_owned())),
+            ]
+        };
+        let string1: Vec<u8> =
+            r#"{"request":{"method":"GET","path":"/"},"tags":["a","b"],"status":200}"#.into();
+
+        let mut pr = Fields::new(fields(), Format::Json, Format::Json);
+        let string2: Vec<u8> =
+            r#"{"request":{"method":"GET"},"tags":[null,"b"],"status":200}"#.into();
+        assert_eq!(
+            pr.run(string1.clone(), &mut Meta::default()).unwrap(),
+            Output::Resume(string2)
+        );
+
+        let mut pr = Fields::new(fields(), Format::Json, Format::Plain);
+        assert_eq!(
+            pr.run(string1, &mut Meta::default()).unwrap(),
+            Output::Resume("GET b 200".into())
+        );
+        assert!(pr.run("no json".into(), &mut Meta::default()).is_err());
+    }
+
+    #[test]
+    fn test_where_json() {
+        let path = Path::new(vec![Segment::Key("tags".to_owned()), Segment::Index(0)]);
+        let mut pr = Where::new(
+            FieldId::Path(path),
+            Regex::new("^a$").unwrap(),
+            Format::Json,
+        );
+
+        let string1: Vec<u8> = r#"{"tags":["a","b"]}"#.into();
+        let string2: Vec<u8> = r#"{"tags":["b","a"]}"#.into();
+        assert_eq!(
+            pr.run(string1.clone(), &mut Meta::default()).unwrap(),
+            Output::Resume(string1)
+        );
+        assert_eq!(
+            pr.run(string2, &mut Meta::default()).unwrap(),
+            Output::Return(None)
+        );
+    }
+
+    #[test]
+    fn test_fields_logfmt() {
+        let fields = || {
+            vec![
+                FieldsAtom::Single(FieldId::Name("msg".to_owned())),
+                FieldsAtom::Single(FieldId::Name("missing".to_owned())),
+                FieldsAtom::Single(FieldId::FromLast(1)),
+            ]
+        };
+        let string1: Vec<u8> = r#"level=info msg="said \"hi\"" ok"#.into();
+
+        let mut pr = Fields::new(fields(), Format::Logfmt, Format::Logfmt);
+        let string2: Vec<u8> = r#"msg="said \"hi\"" ok"#.into();
+        assert_eq!(
+            pr.run(string1.clone(), &mut Meta::default()).unwrap(),
+            Output::Resume(string2)
+        );
+
+        let mut pr = Fields::new(fields(), Format::Logfmt, Format::Plain);
+        let string2: Vec<u8> = r#"said "hi""#.into();
+        assert_eq!(
+            pr.run(string1, &mut Meta::default()).unwrap(),
+            Output::Resume(string2)
+        );
+    }
+
+    #[test]
+    fn test_with() {
+        use Output::*;
+
+        let re = Regex::new("ms$").unwrap();
+        let sub = Sub::new(re, parse_replacement("").unwrap(), true);
+        let mut pr = With::new(
+            FieldId::Name("duration".to_owned()),
+            Program::new(vec![sub.into()]),
+            Format::Logfmt,
+        );
+
+        let string1: Vec<u8> = "level=info duration=120ms".into();
+        let string2: Vec<u8> = "level=info duration=120".into();
+        let string3: Vec<u8> = "level=info".into();
+        assert_eq!(
+            pr.run(string1, &mut Meta::default()).unwrap(),
+            Resume(string2)
+        );
+        assert_eq!(
+            pr.run("duration=3s".into(), &mut Meta::default()).unwrap(),
+            Return(None)
+        );
+        assert_eq!(
+            pr.run(string3.clone(), &mut Meta::default()).unwrap(),
+            Resume(string3)
+        );
+    }
+
+    #[test]
+    fn test_captures() {
+        let regex = |re| Regex::new(re).unwrap();
+        let mut pr = Program::new(vec![
+            Filter::new(regex(r"(?P<key>\w+) = ")).into(),
+            Sub::new(
+                regex(r"_(?P<n>value\d)"),
+                parse_replacement("").unwrap(),
+                false,
+            )
+            .into(),
+        ]);
+
+        let mut meta = Meta::default();
+        assert_eq!(
+            pr.run(LINE3.into(), &mut meta).unwrap(),
+            Some("key1 = header1".into())
+        );
+        // the captured text was replaced
+        let capture = |name: &str, value: &str, range| Capture {
+            name: name.to_owned(),
+            value: value.into(),
+            range,
+        };
+        assert_eq!(meta.captures, vec![capture("n", "value1", None)]);
+
+        let mut meta = Meta::default();
+        pr.run("key = value".into(), &mut meta).unwrap();
+        assert_eq!(meta.captures, vec![capture("key", "key", Some(0..3))]);
+
+        // nothing is recorded unless it is used
+        let mut meta = Meta {
+            track: Track {
+                captures: false,
+                positions: false,
+            },
+            ..Meta::default()
+        };
+        pr.run("key = value".into(), &mut meta).unwrap();
+        assert_eq!(meta.captures, vec![]);
+        assert_eq!(meta.spans, vec![]);
+        assert!(!pr.uses_captures());
+    }
+
+    #[test]
+    fn test_spans() {
+        let regex = |re| Regex::new(re).unwrap();
+        let mut pr = Program::new(vec![
+            Filter::new(regex("header")).into(),
+            Gsub::new(regex("_"), parse_replacement("--").unwrap(), false).into(),
+            Enumeration::new().into(),
+        ]);
+
+        let mut meta = Meta::default();
+        assert_eq!(
+            pr.run(LINE3.into(), &mut meta).unwrap(),
+            Some("1 key1 = header1--value1".into())
+        );
+        assert_eq!(
+            meta.spans,
+            vec![(9..15, Highlight::Match), (16..18, Highlight::Insertion)]
+        );
+
+        // the match overlaps the replaced range
+        let mut pr = Program::new(vec![
+            Filter::new(regex("1_v")).into(),
+            Sub::new(regex("_"), parse_replacement("").unwrap(), false).into(),
+        ]);
+        let mut meta = Meta::default();
+        pr.run(LINE3.into(), &mut meta).unwrap();
+        assert_eq!(meta.spans, vec![]);
+    }
+
+    #[test]
+    fn test_require_forbid() {
+        let regex = |re| Regex::new(re).unwrap();
+        let check = |pr: &mut Program, lines: &[&str]| {
+            run_lines(pr, lines)
+                .1
+                .into_iter()
+                .map(|v| (v.line, v.message))
+                .collect::<Vec<_>>()
+        };
+
+        let mut pr = Program::new(vec![
+            FilterRange::new(regex(r"^\["), regex("^$")).into(),
+            Require::new(regex("^key2")).into(),
+        ]);
+        assert_eq!(check(&mut pr, &LINES), vec![]);
+
+        // all blocks are checked
+        let mut pr = Program::new(vec![
+            FilterRange::new(regex(r"^\["), regex("^$")).into(),
+            Require::new(regex("header1")).into(),
+        ]);
+        assert_eq!(
+            check(&mut pr, &LINES),
+            vec![(
+                6,
+                "No match of required 'header1' in block starting here".to_owned()
+            )]
+        );
+
+        // no input is no match
+        let mut pr = Program::new(vec![Require::new(regex("header2")).into()]);
+        let missing = "No match of required 'header2' in the input".to_owned();
+        assert_eq!(check(&mut pr, &[]), vec![(1, missing.clone())]);
+        let mut pr = Program::new(vec![
+            Filter::new(regex("missing")).into(),
+            Require::new(regex("header2")).into(),
+        ]);
+        assert_eq!(check(&mut pr, &LINES), vec![(8, missing)]);
+
+        let mut pr = Program::new(vec![Forbid::new(regex("value1")).into()]);
+        let forbidden = "Match of forbidden 'value1'".to_owned();
+        assert_eq!(
+            check(&mut pr, &LINES),
+            vec![(3, forbidden.clone()), (7, forbidden)]
+        );
+    }
+
+    #[test]
+    fn test_split_to() {
+        let dir = std::env::temp_dir().join(format!("saw-test-split-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let template = format!("{}/${{section}}.txt", dir.display());
+        let all = dir.join("all.txt");
+
+        let regex = |re| Regex::new(re).unwrap();
+        let mut pr = Program::new(vec![
+            FilterRange::new(regex(r"^\[(?P<section>.*)\]"), regex("^$")).into(),
+            SplitTo::new(parse_replacement(&template).unwrap()).into(),
+            WriteFile::new(all.display().to_string()).into(),
+        ]);
+        let lines = [LINE1, LINE2, LINE3, LINE4, LINE5, LINE6, LINE7, LINE8];
+        for line in lines {
+            pr.run(line.into(), &mut Meta::default()).unwrap();
+        }
+        pr.finish(&mut Meta::default()).unwrap();
+
+        let read = |name| std::fs::read_to_string(dir.join(name)).unwrap();
+        // the end of the block keeps the captures of its start
+        assert_eq!(read("Header 1.txt"), lines[1..5].join("\n") + "\n");
+        assert_eq!(read("Header 2.txt"), lines[5..].join("\n") + "\n");
+        assert_eq!(read("all.txt"), lines[1..].join("\n") + "\n");
+
+        // values can't reach outside of the directory
+        let mut pr = Program::new(vec![
+            Filter::new(regex(r"^\[(?P<section>.*)\]")).into(),
+            SplitTo::new(parse_replacement(&template).unwrap()).into(),
+        ]);
+        for line in ["[../x]", "[x/y]", "[x\0]"] {
+            assert!(pr.run(line.into(), &mut Meta::default()).is_err());
+        }
+
+        // values that weren't captured are missing
+        let mut pr = Program::new(vec![
+            FilterRange::new(regex(r"^\["), regex("^$")).into(),
+            SplitTo::new(parse_replacement(&template).unwrap()).into(),
+        ]);
+        assert!(pr.run(LINE2.into(), &mut Meta::default()).is_err());
+
+        // files closed in between are appended to
+        let template = format!("{}/${{n}}.txt", dir.display());
+        let mut pr = Program::new(vec![
+            Filter::new(regex(r"(?P<n>\d+)")).into(),
+            SplitTo::new(parse_replacement(&template).unwrap()).into(),
+        ]);
+        for n in (0..=MAX_OPEN_FILES).chain([0]) {
+            pr.run(n.to_string().into(), &mut Meta::default()).unwrap();
+        }
+        pr.finish(&mut Meta::default()).unwrap();
+        assert_eq!(read("0.txt"), "0\n0\n");
+        assert_eq!(read("1.txt"), "1\n");
+
+        // records are written like they are printed
+        let mut pr = Program::new(vec![WriteFile::new(all.display().to_string()).into()]);
+        for (line_break, terminator) in [("\r\n", "\r\n"), ("\n", "\0")] {
+            let mut meta = Meta {
+                line_break,
+                terminator,
+                ..Meta::default()
+            };
+            pr.run("a\nb".into(), &mut meta).unwrap();
+        }
+        pr.finish(&mut Meta::default()).unwrap();
+        assert_eq!(read("all.txt"), "a\r\nb\r\na\nb\0");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_file() {
+        let path = std::env::temp_dir().join(format!("saw-test-read-{}", std::process::id()));
+        std::fs::write(&path, "a\nb\n").unwrap();
+        let path = path.display().to_string();
+
+        let mut pr = ReadFile::new(path.clone(), false);
+        let mut meta = Meta::default();
+        assert_eq!(
+            pr.run(LINE2.into(), &mut meta).unwrap(),
+            Output::Resume(LINE2.into())
+        );
+        assert_eq!(meta.appended, vec![b"a", b"b"]);
+
+        let mut pr = ReadFile::new(path.clone(), true);
+        let mut meta = Meta::default();
+        assert_eq!(
+            pr.run(LINE2.into(), &mut meta).unwrap(),
+            Output::Return(None)
+        );
+        assert_eq!(meta.appended, vec![b"a", b"b"]);
+
+        // a group reran until the result stops changing appends only once
+        let mut pr = Repeat::new(
+            Program::new(vec![
+                ReadFile::new(path.clone(), false).into(),
+                Sub::new(
+                    Regex::new("1").unwrap(),
+                    parse_replacement("").unwrap(),
+                    false,
+                )
+                .into(),
+            ]),
+            10,
+        );
+        let mut meta = Meta::default();
+        pr.run(LINE3.into(), &mut meta).unwrap();
+        assert_eq!(meta.appended, vec![b"a", b"b"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_map() {
+        let mapping: HashMap<_, _> = [("key1", "first"), ("header1_value2", "v2")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let map = |selector, unmapped| Map::new(mapping.clone(), selector, unmapped, Format::Plain);
+
+        let mut pr = map(Selector::Field(FieldId::Int(1)), Unmapped::Keep);
+        let mut meta = Meta::default();
+        assert_eq!(
+            pr.run(LINE3.into(), &mut meta).unwrap(),
+            Output::Resume("first = header1_value1".into())
+        );
+        assert_eq!(
+            pr.run(LINE4.into(), &mut meta).unwrap(),
+            Output::Resume(LINE4.into())
+        );
+
+        let mut pr = map(
+            Selector::Field(FieldId::Int(1)),
+            Unmapped::Default("?".to_owned()),
+        );
+        assert_eq!(
+            pr.run(LINE4.into(), &mut meta).unwrap(),
+            Output::Resume("? = header1_value2".into())
+        );
+
+        let mut pr = Program::new(vec![
+            Filter::new(Regex::new(r"= (?P<value>\w+)").unwrap()).into(),
+            map(Selector::Capture("value".to_owned()), Unmapped::Discard).into(),
+        ]);
+        assert_eq!(
+            pr.run(LINE4.into(), &mut Meta::default()).unwrap(),
+            Some("key2 = v2".into())
+        );
+        assert_eq!(pr.run(LINE3.into(), &mut Meta::default()).unwrap(), None);
+
+        // the input can't be changed without the position of the capture
+        let mut pr = map(Selector::Capture("value".to_owned()), Unmapped::Keep);
+        let mut meta = Meta {
+            captures: vec![Capture {
+                name: "value".to_owned(),
+                value: "header1_value2".into(),
+                range: None,
+            }],
+            ..Meta::default()
+        };
+        assert_eq!(
+            pr.run(LINE4.into(), &mut meta).unwrap(),
+            Output::Resume(LINE4.into())
+        );
+    }
+
+    #[test]
+    fn test_filter_in() {
+        let set: HashSet<_> = vec!["key1".to_owned()].into_iter().collect();
+        let field = Selector::Field(FieldId::Int(1));
+
+        let mut pr = FilterIn::new(set.clone(), field.clone(), false, Format::Plain);
+        let mut meta = Meta::default();
+        assert_eq!(
+            pr.run(LINE3.into(), &mut meta).unwrap(),
+            Output::Resume(LINE3.into())
+        );
+        assert_eq!(
+            pr.run(LINE4.into(), &mut meta).unwrap(),
+            Output::Return(None)
+        );
+
+        let mut pr = FilterIn::new(set, field, true, Format::Plain);
+        assert_eq!(
+            pr.run(LINE3.into(), &mut meta).unwrap(),
+            Output::Return(None)
+        );
+        assert_eq!(
+            pr.run(LINE4.into(), &mut meta).unwrap(),
+            Output::Resume(LINE4.into())
+        );
+    }
+
+    #[test]
+    fn test_join() {
+        let row = |s: &str| Format::Plain.split(s).unwrap();
+        let table = || Table {
+            header: Some(row("name value other")),
+            rows: vec![row("key1 1 a"), row("key2 2 b"), row("key1 3 c")],
+        };
+        let fields = vec![FieldsAtom::Range(OpenRange::new(
+            Some(FieldId::Name("value".to_owned())),
+            None,
+        ))];
+        let join = |left, separator: Option<&str>| {
+            Join::new(
+                FieldId::Int(1),
+                table(),
+                FieldId::Name("name".to_owned()),
+                fields.clone(),
+                left,
+                separator.map(From::from),
+                Format::Plain,
+            )
+            .unwrap()
+        };
+
+        let mut pr = join(false, None);
+        let mut meta = Meta::default();
+        assert_eq!(pr.header("key".into()).unwrap(), b"key value other");
+        assert_eq!(
+            pr.run(LINE3.into(), &mut meta).unwrap(),
+            Output::Several(vec![
+                format!("{} 1 a", LINE3).into(),
+                format!("{} 3 c", LINE3).into()
+            ])
+        );
+        assert_eq!(
+            pr.run("key3".into(), &mut meta).unwrap(),
+            Output::Return(None)
+        );
+
+        let mut pr = join(true, None);
+        assert_eq!(
+            pr.run("key3".into(), &mut meta).unwrap(),
+            Output::Resume("key3".into())
+        );
+
+        let mut pr = join(false, Some(" | "));
+        assert_eq!(pr.header("key".into()).unwrap(), b"key | value | other");
+
+        // every matching row is a record of its own for the following atoms
+        let mut pr = Program::new(vec![
+            join(false, None).into(),
+            Sub::new(
+                Regex::new("$").unwrap(),
+                parse_replacement(";").unwrap(),
+                false,
+            )
+            .into(),
+        ]);
+        let mut meta = Meta::default();
+        assert_eq!(
+            pr.run("key1".into(), &mut meta).unwrap(),
+            Some("key1 3 c;".into())
+        );
+        let emitted: Vec<_> = meta.emitted.iter().map(|(res, _)| res.clone()).collect();
+        assert_eq!(emitted, vec![Some("key1 1 a;".into())]);
+    }
+
+    #[test]
+    fn test_exec() {
+        let regex = |re| Regex::new(re).unwrap();
+        let exec = |command, per_block| {
+            Exec::new(parse_replacement(command).unwrap(), per_block, None).into()
+        };
+
+        let mut pr = Program::new(vec![
+            Filter::new(regex(r"(?P<key>\w+) = (?P<value>.*)")).into(),
+            exec("echo $value; tr a-z A-Z", false),
         ]);
+        assert_eq!(
+            pr.run(LINE3.into(), &mut Meta::default()).unwrap(),
+            Some("header1_value1\nKEY1 = HEADER1_VALUE1".into())
+        );
+
+        // captures are quoted for the shell
+        let mut pr = Program::new(vec![
+            Filter::new(regex(r"(?P<word>.*)")).into(),
+            exec("echo $word", false),
+        ]);
+        assert_eq!(
+            pr.run("it's $HOME; exit 1".into(), &mut Meta::default())
+                .unwrap(),
+            Some("it's $HOME; exit 1".into())
+        );
 
-        let string1 = "1 2 3 4 5 6 7".to_owned();
-        let string2 = "1 3 4 5 6".to_owned();
-        assert_eq!(pr.run(string1), Output::Resume(string2));
+        // the output of each block is emitted when the next one starts and at
+        // the end of the input
+        let mut pr = Program::new(vec![
+            FilterRange::new(regex(r"^\[Header (?P<n>\d)"), regex("^$")).into(),
+            exec("sort -r; echo $n", true),
+        ]);
+        let printed: Vec<_> = run_lines(&mut pr, &LINES)
+            .0
+            .into_iter()
+            .map(|(text, meta)| (text, meta.line))
+            .collect();
+        assert_eq!(
+            printed,
+            vec![
+                (format!("{}\n{}\n{}\n\n1", LINE4, LINE3, LINE2), 2),
+                (format!("{}\n{}\n{}\n2", LINE8, LINE7, LINE6), 6),
+            ]
+        );
+
+        let mut pr = Program::new(vec![exec("cat >/dev/null; echo oops >&2; exit 3", false)]);
+        let err = pr.run(LINE3.into(), &mut Meta::default()).unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("failed with exit status: 3: oops"));
+        let mut pr = Program::new(vec![exec("false", false)]);
+        let err = pr.run(LINE3.into(), &mut Meta::default()).unwrap_err();
+        assert!(err.to_string().ends_with("failed with exit status: 1"));
+
+        // commands started by the shell are killed as well
+        let timeout = Some(Duration::from_millis(100));
+        let mut pr = Program::new(vec![Exec::new(
+            parse_replacement("sleep 5; echo").unwrap(),
+            false,
+            timeout,
+        )
+        .into()]);
+        let start = std::time::Instant::now();
+        let meta = &mut Meta {
+            line: 3,
+            ..Meta::default()
+        };
+        let err = pr.run(LINE3.into(), meta).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let err = err.downcast::<LineError>().unwrap();
+        assert_eq!(err.line, 3);
+        assert!(err.message.ends_with("timed out after 100ms"));
+
+        // the output for the values of a field is put back into a record
+        let mut pr = Program::new(vec![With::new(
+            FieldId::Int(2),
+            Program::new(vec![exec("sort -r", true)]),
+            Format::Plain,
+        )
+        .into()]);
+        for line in ["a b", "c d"] {
+            assert_eq!(pr.run(line.into(), &mut Meta::default()).unwrap(), None);
+        }
+        let mut meta = Meta::default();
+        pr.finish(&mut meta).unwrap();
+        let emitted: Vec<_> = meta.emitted.into_iter().map(|(res, _)| res).collect();
+        assert_eq!(emitted, vec![Some("c d\nb".into())]);
+    }
+
+    #[test]
+    fn test_collect() {
+        let regex = |re| Regex::new(re).unwrap();
+        let mut pr = Program::new(vec![
+            MatchRange::new(regex(r"^\[(?P<header>[^\]]*)"), regex("^$")).into(),
+            Collect::new(b", ".to_vec()).into(),
+            Gsub::new(regex(r"key\d = "), parse_replacement("").unwrap(), false).into(),
+        ]);
+        let res: Vec<_> = run_lines(&mut pr, &LINES)
+            .0
+            .into_iter()
+            .map(|(text, meta)| (text, meta.line, meta.capture("header").cloned()))
+            .collect();
+
+        let header = |value: &str| {
+            Some(Capture {
+                name: "header".to_owned(),
+                value: value.into(),
+                range: None,
+            })
+        };
+        // the first block ends with the empty line, the second one with the
+        // input
+        assert_eq!(
+            res,
+            vec![
+                ("".to_owned(), 1, None),
+                (
+                    "[Header 1], header1_value1, header1_value2, ".to_owned(),
+                    2,
+                    header("Header 1")
+                ),
+                (
+                    "[Header 2], header2_value1, header2_value2".to_owned(),
+                    6,
+                    header("Header 2")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collect_nested() {
+        let regex = |re| Regex::new(re).unwrap();
+        let sub = |re, replacement| {
+            Sub::new(regex(re), parse_replacement(replacement).unwrap(), false).into()
+        };
+        let run = |pr: &mut Program, lines: &[&str]| -> Vec<_> {
+            run_lines(pr, lines)
+                .0
+                .into_iter()
+                .map(|(text, _)| text)
+                .collect()
+        };
+
+        // blocks ending within the group and at the end of the input pass
+        // the atoms after it
+        let mut pr = Program::new(vec![
+            Repeat::new(
+                Program::new(vec![
+                    FilterRange::new(regex(r"^\["), regex("^$")).into(),
+                    Collect::new(b"|".to_vec()).into(),
+                ]),
+                1,
+            )
+            .into(),
+            sub(r"\|", "+"),
+        ]);
+        assert_eq!(
+            run(&mut pr, &LINES),
+            vec![
+                format!("{}+{}|{}|", LINE2, LINE3, LINE4),
+                format!("{}+{}|{}", LINE6, LINE7, LINE8),
+            ]
+        );
+
+        // collected values are put back into a record
+        let mut pr = Program::new(vec![
+            With::new(
+                FieldId::Int(1),
+                Program::new(vec![
+                    FilterRange::new(regex("^s"), regex("^e")).into(),
+                    Collect::new(b",".to_vec()).into(),
+                ]),
+                Format::Plain,
+            )
+            .into(),
+            sub(",", ";"),
+        ]);
+        assert_eq!(
+            run(&mut pr, &["s 1", "x 2", "e 3", "y 4", "s 5", "z 6"]),
+            vec!["s;x,e 3", "s;z 6"]
+        );
     }
 }
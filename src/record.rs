@@ -0,0 +1,213 @@
+use crate::json;
+use crate::logfmt::{self, Pair};
+use crate::program::FieldId;
+use crate::Result;
+use anyhow::bail;
+use serde_json::Value;
+use std::fmt;
+use std::mem;
+
+// Determines how inputs are split into fields and how fields are joined again
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Format {
+    Plain,           // fields separated by spaces
+    Delimited(char), // CSV, TSV etc. with RFC 4180 quoting
+    Json,            // JSON documents; fields are addressed by path
+    Logfmt,          // key=value pairs; fields are addressed by key or position
+}
+
+impl Format {
+    // JSON and logfmt records are made of keys and values, not of a list of
+    // fields, so they can't be split
+    pub fn split(&self, record: &str) -> Result<Vec<String>> {
+        match self {
+            Format::Plain => Ok(record
+                .split(' ')
+                .filter(|s| !s.is_empty())
+                .map(From::from)
+                .collect()),
+            Format::Delimited(sep) => split_delimited(record, *sep),
+            Format::Json | Format::Logfmt => bail!("{} records can't be split into fields", self),
+        }
+    }
+
+    // Returns the value of a single field of `record` as text
+    pub fn get(&self, record: &str, field: &FieldId) -> Result<Option<String>> {
+        match self {
+            Format::Json => Ok(field
+                .to_path()?
+                .get(&parse_json(record)?)
+                .map(json::to_text)),
+            Format::Logfmt => {
+                let pairs = logfmt::parse(record);
+                Ok(logfmt_position(&pairs, field)?.map(|n| pairs[n].1.clone().unwrap_or_default()))
+            }
+            _ => Ok(field.get(&self.split(record)?)?.cloned()),
+        }
+    }
+
+    // Replaces the value of a single field of `record`, if present
+    pub fn set(&self, record: &str, field: &FieldId, value: String) -> Result<String> {
+        match self {
+            Format::Json => {
+                let mut doc = parse_json(record)?;
+                let path = field.to_path()?;
+                let new = match path.get(&doc) {
+                    None => return Ok(record.to_owned()),
+                    Some(Value::String(_)) => Value::String(value),
+                    // keep the type of e.g. numbers if possible
+                    Some(_) => serde_json::from_str(&value).unwrap_or(Value::String(value)),
+                };
+                path.insert(&mut doc, new);
+                Ok(doc.to_string())
+            }
+            Format::Logfmt => {
+                let mut pairs = logfmt::parse(record);
+                if let Some(n) = logfmt_position(&pairs, field)? {
+                    pairs[n].1 = Some(value);
+                }
+                Ok(logfmt::join(&pairs.iter().collect::<Vec<_>>()))
+            }
+            _ => {
+                let mut fields = self.split(record)?;
+                let len = fields.len();
+                if let Some(n) = field.to_usize(len)?.checked_sub(1).filter(|n| *n < len) {
+                    fields[n] = value;
+                }
+                self.join(&fields)
+            }
+        }
+    }
+
+    // Separator of the fields, as written by `join`
+    pub fn separator(&self) -> Result<char> {
+        match self {
+            Format::Plain => Ok(' '),
+            Format::Delimited(sep) => Ok(*sep),
+            Format::Json | Format::Logfmt => bail!("{} records can't be joined from fields", self),
+        }
+    }
+
+    // Like `split`, only applies to plain and delimited formats
+    pub fn join<S: AsRef<str>>(&self, fields: &[S]) -> Result<String> {
+        match self {
+            Format::Plain => Ok(fields.iter().map(AsRef::as_ref).intersperse(" ").collect()),
+            Format::Delimited(sep) => Ok(fields
+                .iter()
+                .map(|field| quote(field.as_ref(), *sep))
+                .intersperse(sep.to_string())
+                .collect()),
+            Format::Json | Format::Logfmt => bail!("{} records can't be joined from fields", self),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Plain => write!(f, "Plain"),
+            Format::Delimited(',') => write!(f, "CSV"),
+            Format::Delimited('\t') => write!(f, "TSV"),
+            Format::Delimited(_) => write!(f, "Delimited"),
+            Format::Json => write!(f, "JSON"),
+            Format::Logfmt => write!(f, "logfmt"),
+        }
+    }
+}
+
+// Quotes that are not at the beginning of a field are taken literally, as is
+// everything following the closing quote of a quoted field.
+fn split_delimited(record: &str, sep: char) -> Result<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut at_start = true;
+    let mut quoted = false;
+    let mut chars = record.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if quoted {
+            if c != '"' {
+                field.push(c);
+            } else if chars.peek() == Some(&'"') {
+                chars.next();
+                field.push('"');
+            } else {
+                quoted = false;
+            }
+        } else if c == sep {
+            fields.push(mem::take(&mut field));
+            at_start = true;
+            continue;
+        } else if c == '"' && at_start {
+            quoted = true;
+        } else {
+            field.push(c);
+        }
+        at_start = false;
+    }
+    if quoted {
+        bail!("Missing closing quote of the last value");
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+// Whether a quoted value of `record` is still open at its end, following the
+// rules of `split_delimited`; separators are ASCII
+pub fn ends_quoted(record: &[u8], sep: char) -> bool {
+    let sep = sep as u8;
+    let mut at_start = true;
+    let mut quoted = false;
+    let mut bytes = record.iter().peekable();
+
+    while let Some(&b) = bytes.next() {
+        if quoted {
+            if b == b'"' && bytes.next_if_eq(&&b'"').is_none() {
+                quoted = false;
+            }
+        } else if b == sep {
+            at_start = true;
+            continue;
+        } else if b == b'"' && at_start {
+            quoted = true;
+        }
+        at_start = false;
+    }
+    quoted
+}
+
+fn quote(field: &str, sep: char) -> String {
+    if field.contains([sep, '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+// A record isn't valid JSON
+#[derive(Debug)]
+pub struct InvalidJson(serde_json::Error);
+
+impl fmt::Display for InvalidJson {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid JSON: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidJson {}
+
+pub fn parse_json(record: &str) -> Result<Value> {
+    Ok(serde_json::from_str(record).map_err(InvalidJson)?)
+}
+
+// 0-based position of the pair selected by `field`; keys are looked up in
+// the record itself
+pub fn logfmt_position(pairs: &[Pair], field: &FieldId) -> Result<Option<usize>> {
+    match field {
+        FieldId::Name(name) => Ok(pairs.iter().position(|(key, _)| key == name)),
+        field => Ok(field
+            .to_usize(pairs.len())?
+            .checked_sub(1)
+            .filter(|n| *n < pairs.len())),
+    }
+}
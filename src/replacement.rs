@@ -0,0 +1,145 @@
+use crate::engine::Captures;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum GroupRef {
+    Index(usize),
+    Name(String),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Case {
+    Upper,     // \U
+    Lower,     // \L
+    UpperNext, // \u
+    LowerNext, // \l
+    End,       // \E
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Piece {
+    Literal(Vec<u8>),
+    Group(GroupRef),
+    Case(Case),
+}
+
+// Replacement string of a substitution, i.e. literal text interspersed with
+// references to capture groups and case conversion escapes
+#[derive(Debug, PartialEq, Clone)]
+pub struct Replacement(Vec<Piece>);
+
+impl Replacement {
+    pub fn new(pieces: Vec<Piece>) -> Replacement {
+        Replacement(pieces)
+    }
+
+    pub fn expand(&self, caps: &Captures, dst: &mut Vec<u8>) {
+        self.expand_with(
+            |group| {
+                match group {
+                    GroupRef::Index(i) => caps.get(*i),
+                    GroupRef::Name(name) => caps.name(name),
+                }
+                .map(|m| m.as_bytes())
+            },
+            dst,
+        )
+    }
+
+    // Names of the referenced groups, in the order of the references
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().filter_map(|piece| match piece {
+            Piece::Group(GroupRef::Name(name)) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    pub fn has_names(&self) -> bool {
+        self.names().next().is_some()
+    }
+
+    pub fn has_indexes(&self) -> bool {
+        self.0
+            .iter()
+            .any(|piece| matches!(piece, Piece::Group(GroupRef::Index(_))))
+    }
+
+    // Expands references to named groups with the values returned by
+    // `named`; references by index are expanded to nothing
+    pub fn expand_named<'s>(&self, named: impl Fn(&str) -> Option<&'s [u8]>, dst: &mut Vec<u8>) {
+        self.expand_with(
+            |group| match group {
+                GroupRef::Index(_) => None,
+                GroupRef::Name(name) => named(name),
+            },
+            dst,
+        )
+    }
+
+    fn expand_with<'s>(&self, group: impl Fn(&GroupRef) -> Option<&'s [u8]>, dst: &mut Vec<u8>) {
+        let mut converter = CaseConverter::new();
+        for piece in &self.0 {
+            match piece {
+                Piece::Literal(s) => converter.push(s, dst),
+                Piece::Group(group_ref) => {
+                    if let Some(s) = group(group_ref) {
+                        converter.push(s, dst);
+                    }
+                }
+                Piece::Case(case) => converter.set(*case),
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Mode {
+    Upper,
+    Lower,
+    Keep,
+}
+
+// Tracks the case conversion that is currently active while expanding a
+// replacement; `next` overrides `mode` for the next character only.
+struct CaseConverter {
+    mode: Mode,
+    next: Option<Mode>,
+}
+
+impl CaseConverter {
+    fn new() -> Self {
+        CaseConverter {
+            mode: Mode::Keep,
+            next: None,
+        }
+    }
+
+    fn set(&mut self, case: Case) {
+        match case {
+            Case::Upper => self.mode = Mode::Upper,
+            Case::Lower => self.mode = Mode::Lower,
+            Case::End => {
+                self.mode = Mode::Keep;
+                self.next = None;
+            }
+            Case::UpperNext => self.next = Some(Mode::Upper),
+            Case::LowerNext => self.next = Some(Mode::Lower),
+        }
+    }
+
+    // Bytes that aren't valid UTF-8 are kept as they are
+    fn push(&mut self, bytes: &[u8], dst: &mut Vec<u8>) {
+        let push = |c: char, dst: &mut Vec<u8>| {
+            dst.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes())
+        };
+        for chunk in bytes.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                match self.next.take().unwrap_or(self.mode) {
+                    Mode::Upper => c.to_uppercase().for_each(|c| push(c, dst)),
+                    Mode::Lower => c.to_lowercase().for_each(|c| push(c, dst)),
+                    Mode::Keep => push(c, dst),
+                }
+            }
+            dst.extend_from_slice(chunk.invalid());
+        }
+    }
+}
@@ -0,0 +1,64 @@
+// Loading of lookup tables used by atoms like `map`, `filter-in` and `join`
+use crate::input::{Encoding, Records, Separator};
+use crate::record::Format;
+use crate::Result;
+use anyhow::{bail, Context};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+// Reads a file with one entry per line; empty lines are skipped
+pub fn load_set(path: &str) -> Result<HashSet<String>> {
+    let content = fs::read_to_string(path).with_context(|| format!("Could not read {}", path))?;
+    Ok(content
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(From::from)
+        .collect())
+}
+
+// Reads a file with one key and value per line, separated by a tab or, if
+// there is none, by the first `=`; empty lines are skipped
+pub fn load_map(path: &str) -> Result<HashMap<String, String>> {
+    let content = fs::read_to_string(path).with_context(|| format!("Could not read {}", path))?;
+    let mut map = HashMap::new();
+    for (n, line) in content.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = match line.split_once('\t').or_else(|| line.split_once('=')) {
+            Some(pair) => pair,
+            None => bail!("{}:{}: Missing tab or '=' after key", path, n + 1),
+        };
+        map.insert(key.to_owned(), value.to_owned());
+    }
+    Ok(map)
+}
+
+// Records of a file split into fields
+pub struct Table {
+    pub header: Option<Vec<String>>,
+    pub rows: Vec<Vec<String>>,
+}
+
+// If `header` is set, the first line is taken as header naming the fields
+pub fn load_table(path: &str, format: Format, header: bool) -> Result<Table> {
+    if let Format::Json | Format::Logfmt = format {
+        bail!("Only plain and delimited records can be joined");
+    }
+    let content = fs::read_to_string(path).with_context(|| format!("Could not read {}", path))?;
+    // quoted values may span several lines
+    let mut rows = Records::new(
+        content.as_bytes(),
+        &Separator::Newline,
+        None,
+        Encoding::Utf8,
+        format,
+    )
+    .map(|record| format.split(std::str::from_utf8(&record?.text)?))
+    .collect::<Result<Vec<_>>>()?
+    .into_iter();
+    Ok(Table {
+        header: if header { rows.next() } else { None },
+        rows: rows.collect(),
+    })
+}